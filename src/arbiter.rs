@@ -0,0 +1,213 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use Presence;
+use PresenceDetail;
+use PresenceProviderType;
+
+struct ProviderState {
+    name: &'static str,
+    key: String,
+    presence: Presence,
+    started: Option<Instant>,
+}
+
+/// Keeps the latest presence reported by each provider instance and decides
+/// which one should be shown. Entries in the priority list are either a
+/// provider name, covering all of its accounts, or "name:label" for a single
+/// account. Earlier entries win; instances that are not listed rank after all
+/// listed ones. Ties go to the title that was started most recently.
+pub struct PresenceArbiter {
+    priority: Vec<String>,
+    states: HashMap<String, ProviderState>,
+}
+
+fn same_title(a: &Presence, b: &Presence) -> bool {
+    match (a, b) {
+        (&Some(ref a), &Some(ref b)) if a.title_id.is_some() && b.title_id.is_some() => {
+            a.device == b.device && a.title_id == b.title_id
+        }
+        (&Some(ref a), &Some(ref b)) => a.device == b.device && a.game == b.game,
+        _ => false,
+    }
+}
+
+impl PresenceArbiter {
+    pub fn new(priority: &[String]) -> PresenceArbiter {
+        PresenceArbiter {
+            priority: priority.to_vec(),
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn set_priority(&mut self, priority: &[String]) {
+        self.priority = priority.to_vec();
+    }
+
+    /// Keeps only the presences for which `keep(name, key)` returns true.
+    pub fn retain<F: Fn(&str, &str) -> bool>(&mut self, keep: F) {
+        let keys = self.states
+            .iter()
+            .filter(|&(_, state)| !keep(state.name, &state.key))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.states.remove(&key);
+        }
+    }
+
+    pub fn update(&mut self, provider_type: &PresenceProviderType, presence: Presence) {
+        let key = provider_type.key();
+        let started = match self.states.get(&key) {
+            Some(state) if same_title(&state.presence, &presence) => state.started,
+            _ => presence.as_ref().map(|_| Instant::now()),
+        };
+
+        self.states.insert(key.clone(),
+                           ProviderState {
+                               name: provider_type.name,
+                               key: key,
+                               presence: presence,
+                               started: started,
+                           });
+    }
+
+    /// Returns the key of the winning provider instance along with what
+    /// `render` made of its presence. Presences that `render` turns into None,
+    /// such as ignored titles, don't compete, so they can't hide a lower
+    /// ranked game.
+    pub fn effective<T, F>(&self, render: F) -> Option<(&str, T)>
+        where F: Fn(&PresenceDetail) -> Option<T>
+    {
+        let mut best: Option<(&ProviderState, T)> = None;
+        for state in self.states.values() {
+            let rendered = match state.presence.as_ref().and_then(|x| render(x)) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            best = match best {
+                Some((b, r)) if self.compare(b, state) != Ordering::Greater => Some((b, r)),
+                _ => Some((state, rendered)),
+            };
+        }
+
+        best.map(|(s, r)| (&s.key[..], r))
+    }
+
+    fn rank(&self, state: &ProviderState) -> usize {
+        self.priority
+            .iter()
+            .position(|x| *x == state.key || x == state.name)
+            .unwrap_or(self.priority.len())
+    }
+
+    fn compare(&self, a: &ProviderState, b: &ProviderState) -> Ordering {
+        match self.rank(a).cmp(&self.rank(b)) {
+            Ordering::Equal => b.started.cmp(&a.started),
+            o => o,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use {ActivityKind, Presence, PresenceDetail, PresenceProviderType};
+
+    fn provider(name: &'static str, label: &str) -> PresenceProviderType {
+        PresenceProviderType {
+            name: name,
+            label: label.to_owned(),
+        }
+    }
+
+    fn playing(game: &str) -> Presence {
+        Some(PresenceDetail {
+            device: "PS4".to_owned(),
+            game: game.to_owned(),
+            title_id: None,
+            extended_info: None,
+            kind: ActivityKind::Playing,
+        })
+    }
+
+    fn arbiter(priority: &[&str]) -> PresenceArbiter {
+        PresenceArbiter::new(&priority.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+    }
+
+    /// The key and game of the winner, rendering every presence as is.
+    fn winner(arbiter: &PresenceArbiter) -> Option<(String, String)> {
+        arbiter.effective(|x| Some(x.game.clone())).map(|(k, g)| (k.to_owned(), g))
+    }
+
+    fn shown(key: &str, game: &str) -> Option<(String, String)> {
+        Some((key.to_owned(), game.to_owned()))
+    }
+
+    #[test]
+    fn labelled_entry_ranks_one_account() {
+        let mut arbiter = arbiter(&["psn:alt", "xbl", "psn"]);
+        arbiter.update(&provider("psn", "main"), playing("Bloodborne"));
+        arbiter.update(&provider("xbl", "main"), playing("Halo 5"));
+        assert_eq!(winner(&arbiter), shown("xbl:main", "Halo 5"));
+
+        arbiter.update(&provider("psn", "alt"), playing("Gran Turismo"));
+        assert_eq!(winner(&arbiter), shown("psn:alt", "Gran Turismo"));
+    }
+
+    #[test]
+    fn plain_name_covers_every_account() {
+        let mut arbiter = arbiter(&["psn", "xbl"]);
+        arbiter.update(&provider("xbl", "main"), playing("Halo 5"));
+        arbiter.update(&provider("psn", "alt"), playing("Bloodborne"));
+        assert_eq!(winner(&arbiter), shown("psn:alt", "Bloodborne"));
+    }
+
+    #[test]
+    fn unlisted_providers_rank_last() {
+        let mut arbiter = arbiter(&["xbl"]);
+        arbiter.update(&provider("xbl", "main"), playing("Halo 5"));
+        thread::sleep(Duration::from_millis(10));
+        arbiter.update(&provider("psn", "main"), playing("Bloodborne"));
+        assert_eq!(winner(&arbiter), shown("xbl:main", "Halo 5"));
+    }
+
+    #[test]
+    fn most_recently_started_wins_a_tie() {
+        let mut arbiter = arbiter(&[]);
+        arbiter.update(&provider("xbl", "main"), playing("Halo 5"));
+        thread::sleep(Duration::from_millis(10));
+        arbiter.update(&provider("psn", "main"), playing("Bloodborne"));
+        assert_eq!(winner(&arbiter), shown("psn:main", "Bloodborne"));
+
+        // the same title reported again keeps its start time
+        thread::sleep(Duration::from_millis(10));
+        arbiter.update(&provider("xbl", "main"), playing("Halo 5"));
+        assert_eq!(winner(&arbiter), shown("psn:main", "Bloodborne"));
+
+        arbiter.update(&provider("xbl", "main"), playing("Forza Horizon 3"));
+        assert_eq!(winner(&arbiter), shown("xbl:main", "Forza Horizon 3"));
+    }
+
+    #[test]
+    fn ignored_or_missing_presence_does_not_hide_lower_ranks() {
+        let mut arbiter = arbiter(&["xbl", "psn"]);
+        arbiter.update(&provider("xbl", "main"), None);
+        arbiter.update(&provider("psn", "main"), playing("Bloodborne"));
+        assert_eq!(winner(&arbiter), shown("psn:main", "Bloodborne"));
+
+        arbiter.update(&provider("xbl", "main"), playing("Ignored Game"));
+        let rendered = arbiter.effective(|x| if x.game == "Ignored Game" {
+                None
+            } else {
+                Some(x.game.clone())
+            })
+            .map(|(k, g)| (k.to_owned(), g));
+        assert_eq!(rendered, shown("psn:main", "Bloodborne"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::{ConfigError, Section};
+
+const BACKOFF_KEYS: &'static [&'static str] =
+    &["initial_delay", "max_delay", "breaker_threshold", "probe_interval"];
+
+/// How a provider slows down after failed updates, read from the optional
+/// `backoff` object of its config section. All delays are in seconds.
+///
+/// After the first failure the provider waits `initial_delay`, doubling with
+/// every further failure up to `max_delay`. After `breaker_threshold` failures
+/// in a row (0 disables this) the provider is paused and only probed every
+/// `probe_interval` until an update succeeds again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BackoffPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub breaker_threshold: u32,
+    pub probe_interval: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> BackoffPolicy {
+        BackoffPolicy {
+            initial_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(600),
+            breaker_threshold: 10,
+            probe_interval: Duration::from_secs(1800),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    pub fn from_section(section: &Section) -> Result<BackoffPolicy, ConfigError> {
+        let default = BackoffPolicy::default();
+        let backoff = match section.object("backoff")? {
+            Some(s) => s,
+            None => return Ok(default),
+        };
+
+        backoff.check_keys(BACKOFF_KEYS)?;
+        let breaker_threshold = match backoff.u64("breaker_threshold")? {
+            Some(n) if n > u32::max_value() as u64 => {
+                return Err(backoff.invalid("breaker_threshold", "too large"))
+            }
+            Some(n) => n as u32,
+            None => default.breaker_threshold,
+        };
+
+        let policy = BackoffPolicy {
+            initial_delay: backoff.duration_secs("initial_delay", default.initial_delay)?,
+            max_delay: backoff.duration_secs("max_delay", default.max_delay)?,
+            breaker_threshold: breaker_threshold,
+            probe_interval: backoff.duration_secs("probe_interval", default.probe_interval)?,
+        };
+
+        if policy.max_delay < policy.initial_delay {
+            return Err(backoff.invalid("max_delay", "must not be less than initial_delay"));
+        }
+
+        Ok(policy)
+    }
+}
+
+fn to_millis(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1000) + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Tracks consecutive failures of one provider and works out how long to wait
+/// before the next update.
+pub struct Backoff {
+    policy: BackoffPolicy,
+    failures: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new(policy: BackoffPolicy) -> Backoff {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() ^ ((x.subsec_nanos() as u64) << 32))
+            .unwrap_or(0);
+
+        Backoff {
+            policy: policy,
+            failures: 0,
+            // xorshift gets stuck on zero
+            rng: seed | 1,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.policy.breaker_threshold > 0 && self.failures >= self.policy.breaker_threshold
+    }
+
+    /// Returns the number of failures that preceded this success.
+    pub fn record_success(&mut self) -> u32 {
+        let failures = self.failures;
+        self.failures = 0;
+        failures
+    }
+
+    /// Returns true if this failure opened the circuit breaker.
+    pub fn record_failure(&mut self) -> bool {
+        let was_open = self.is_open();
+        self.failures = self.failures.saturating_add(1);
+        !was_open && self.is_open()
+    }
+
+    /// `interval` is the regular update interval, which is used as is while
+    /// there are no failures and is never undercut while backing off.
+    pub fn next_delay(&mut self, interval: Duration) -> Duration {
+        if self.failures == 0 {
+            return interval;
+        }
+
+        let delay = if self.is_open() {
+            self.policy.probe_interval
+        } else {
+            let exponent = cmp::min(self.failures - 1, 16);
+            let millis = to_millis(self.policy.initial_delay).saturating_mul(1 << exponent);
+            cmp::min(Duration::from_millis(millis), self.policy.max_delay)
+        };
+
+        cmp::max(self.jitter(delay), interval)
+    }
+
+    /// Picks a random delay between half and all of `delay`, so providers that
+    /// failed together don't all retry at the same moment.
+    fn jitter(&mut self, delay: Duration) -> Duration {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        let half = to_millis(delay) / 2;
+        Duration::from_millis(half + self.rng % (half + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_hjson::{self, Value as HJsonValue};
+
+    use super::*;
+    use config::Section;
+
+    fn policy(threshold: u32) -> BackoffPolicy {
+        BackoffPolicy {
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            breaker_threshold: threshold,
+            probe_interval: Duration::from_secs(600),
+        }
+    }
+
+    fn from_hjson(hjson: &str) -> Result<BackoffPolicy, ConfigError> {
+        match serde_hjson::from_str(hjson).unwrap() {
+            HJsonValue::Object(o) => BackoffPolicy::from_section(&Section::root(&o)),
+            _ => panic!("expected an object"),
+        }
+    }
+
+    fn assert_jittered(delay: Duration, full: Duration) {
+        assert!(delay <= full, "{:?} is over {:?}", delay, full);
+        assert!(delay >= full / 2, "{:?} is under half of {:?}", delay, full);
+    }
+
+    #[test]
+    fn no_failures_uses_the_interval() {
+        let mut backoff = Backoff::new(policy(0));
+        assert_eq!(backoff.next_delay(Duration::from_secs(30)), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(policy(0));
+        for &full in &[10, 20, 40, 60, 60, 60] {
+            backoff.record_failure();
+            assert_jittered(backoff.next_delay(Duration::from_secs(0)),
+                            Duration::from_secs(full));
+        }
+
+        assert_eq!(backoff.record_success(), 6);
+        assert_eq!(backoff.next_delay(Duration::from_secs(5)), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_stays_in_range_and_above_the_interval() {
+        let mut backoff = Backoff::new(policy(0));
+        backoff.record_failure();
+        for seed in 1..200 {
+            backoff.rng = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+            assert_jittered(backoff.next_delay(Duration::from_secs(0)),
+                            Duration::from_secs(10));
+            assert!(backoff.next_delay(Duration::from_secs(8)) >= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn breaker_opens_once() {
+        let mut backoff = Backoff::new(policy(3));
+        assert!(!backoff.record_failure());
+        assert!(!backoff.record_failure());
+        assert!(!backoff.is_open());
+        assert!(backoff.record_failure());
+        assert!(backoff.is_open());
+        assert!(!backoff.record_failure());
+        assert_eq!(backoff.failures(), 4);
+
+        backoff.record_success();
+        assert!(!backoff.is_open());
+    }
+
+    #[test]
+    fn open_breaker_waits_the_probe_interval() {
+        let mut backoff = Backoff::new(policy(2));
+        backoff.record_failure();
+        backoff.record_failure();
+        assert_jittered(backoff.next_delay(Duration::from_secs(0)),
+                        Duration::from_secs(600));
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let mut backoff = Backoff::new(policy(0));
+        for _ in 0..100 {
+            assert!(!backoff.record_failure());
+        }
+        assert!(!backoff.is_open());
+    }
+
+    #[test]
+    fn reads_config_section() {
+        assert_eq!(from_hjson("{}").unwrap(), BackoffPolicy::default());
+        assert_eq!(from_hjson(r#"{"backoff": {"initial_delay": 10, "max_delay": 60,
+                                              "breaker_threshold": 0, "probe_interval": 600}}"#)
+                       .unwrap(),
+                   policy(0));
+        assert!(from_hjson(r#"{"backoff": {"initial_delay": 60, "max_delay": 10}}"#).is_err());
+        assert!(from_hjson(r#"{"backoff": {"initial_delay": 0}}"#).is_err());
+        assert!(from_hjson(r#"{"backoff": {"retries": 3}}"#).is_err());
+    }
+}
@@ -1,14 +1,18 @@
-mod file;
+mod section;
+mod title;
 
 use std::io;
-use std::fs::File;
-use std::time::Duration;
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
 use std::collections::HashMap;
-use self::file::ConfigFile;
+pub use self::section::Section;
+pub use self::title::TitleRules;
 use serde_hjson::Value as HJsonValue;
 
 use HJsonObject;
 use serde_hjson;
+use hyper::Url;
+use template::{Template, TemplateError};
 
 quick_error! {
     #[derive(Debug)]
@@ -25,9 +29,22 @@ quick_error! {
             display("HJSON parsing error: {}", err)
             cause(err)
         }
-        Invalid {
-            description("invalid config")
-            display("Invalid config")
+        UnknownKey(path: String, expected: String) {
+            description("unknown config key")
+            display("Unknown key '{}' (expected one of: {})", path, expected)
+        }
+        MissingKey(path: String) {
+            description("missing config key")
+            display("Missing required key '{}'", path)
+        }
+        InvalidValue(path: String, reason: String) {
+            description("invalid config value")
+            display("Invalid value for '{}': {}", path, reason)
+        }
+        Template(path: String, err: TemplateError) {
+            description("invalid template")
+            display("Invalid template '{}': {}", path, err)
+            cause(err)
         }
     }
 }
@@ -39,47 +56,455 @@ pub enum TitleSetting {
     Full,
 }
 
+const DEFAULT_STATUS_FORMAT: &'static str = "{device}: {game}{? {extended_info}}";
+const DEFAULT_MEDIA_FORMAT: &'static str = "{device}: Watching {game}";
+const DEFAULT_UPDATE_INTERVAL: u64 = 30;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+pub const PROVIDER_NAMES: &'static [&'static str] = &["xbl", "psn", "dummy"];
+
+const TOP_LEVEL_KEYS: &'static [&'static str] = &["discord_token",
+                                                  "discord",
+                                                  "update_interval",
+                                                  "shutdown_timeout",
+                                                  "status_format",
+                                                  "media_format",
+                                                  "normalize_titles",
+                                                  "provider_priority",
+                                                  "title_settings",
+                                                  "title_rules",
+                                                  "http",
+                                                  "xbl",
+                                                  "psn",
+                                                  "dummy"];
+const DISCORD_USER_KEYS: &'static [&'static str] =
+    &["token", "label", "providers", "title_settings", "title_rules"];
+
+/// A Discord user whose status is kept up to date. Either the single
+/// `discord_token` or one entry of the `discord` list.
+pub struct DiscordUserConfig {
+    pub label: String,
+    pub token: String,
+    /// Provider names or "name:label" keys whose presence this user shows.
+    /// Empty shows every provider.
+    pub providers: Vec<String>,
+    /// Checked before the top level title settings and rules.
+    pub title_rules: TitleRules,
+}
+
+impl DiscordUserConfig {
+    pub fn shows(&self, name: &str, key: &str) -> bool {
+        self.providers.is_empty() || self.providers.iter().any(|x| x == name || *x == key)
+    }
+}
+
 pub struct PresenceMonitorConfig {
-    pub discord_token: String,
+    pub discord_users: Vec<DiscordUserConfig>,
     pub update_interval: Duration,
-    pub title_settings: HashMap<String, TitleSetting>,
+    /// How long to wait for the Discord status to be cleared when exiting.
+    pub shutdown_timeout: Duration,
+    pub status_format: Template,
+    pub media_format: Template,
+    pub normalize_titles: bool,
+    pub title_rules: TitleRules,
+    pub provider_priority: Vec<String>,
     pub json: HJsonObject,
+    pub path: String,
+    pub modified: Option<SystemTime>,
 }
 
 impl PresenceMonitorConfig {
     pub fn from_file(path: &str) -> Result<PresenceMonitorConfig, ConfigError> {
-        let json = {
-            let file = File::open(path)?;
-            match serde_hjson::from_reader(file)? {
-                HJsonValue::Object(o) => o,
-                _ => return Err(ConfigError::Invalid),
+        // read before the contents so a write during loading triggers another reload
+        let modified = modified_time(path);
+
+        let file = File::open(path)?;
+        let mut config = match serde_hjson::from_reader(file)? {
+            HJsonValue::Object(o) => PresenceMonitorConfig::from_json(o)?,
+            _ => {
+                return Err(ConfigError::InvalidValue("<root>".to_owned(),
+                                                     "expected an object".to_owned()))
             }
         };
 
-        let config: ConfigFile = {
-            let file = File::open(path)?;
-            serde_hjson::from_reader(file)?
+        config.path = path.to_owned();
+        config.modified = modified;
+        Ok(config)
+    }
+
+    /// Validates everything except the provider sections, which are checked
+    /// by each provider's `from_config`.
+    fn from_json(json: HJsonObject) -> Result<PresenceMonitorConfig, ConfigError> {
+        let mut config = {
+            let root = Section::root(&json);
+            root.check_keys(TOP_LEVEL_KEYS)?;
+
+            let update_interval =
+                root.duration_secs("update_interval",
+                                   Duration::from_secs(DEFAULT_UPDATE_INTERVAL))?;
+
+            let shutdown_timeout =
+                root.u64("shutdown_timeout")?.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+
+            let status_format = root.string("status_format")?.unwrap_or(DEFAULT_STATUS_FORMAT);
+            let media_format = root.string("media_format")?.unwrap_or(DEFAULT_MEDIA_FORMAT);
+
+            let provider_priority = root.string_array("provider_priority")?.unwrap_or(Vec::new());
+            check_provider_refs(&root.key_path("provider_priority"), &provider_priority)?;
+
+            PresenceMonitorConfig {
+                discord_users: PresenceMonitorConfig::read_discord_users(&root)?,
+                update_interval: update_interval,
+                shutdown_timeout: Duration::from_secs(shutdown_timeout),
+                status_format: PresenceMonitorConfig::parse_template("status_format",
+                                                                     status_format)?,
+                media_format: PresenceMonitorConfig::parse_template("media_format", media_format)?,
+                normalize_titles: root.bool("normalize_titles")?.unwrap_or(true),
+                title_rules: TitleRules::from_config(&root)?,
+                provider_priority: provider_priority,
+                json: HJsonObject::new(),
+                path: String::new(),
+                modified: None,
+            }
         };
 
-        let mut title_settings: HashMap<String, TitleSetting> = HashMap::new();
-        for pair in config.title_settings.iter().flat_map(|ref x| x.iter()) {
-            title_settings.insert(pair.0.clone(),
-                                  PresenceMonitorConfig::convert_title_setting(pair.1));
+        config.json = json;
+        Ok(config)
+    }
+
+    /// Reads either `discord_token` for a single user or the `discord` list of
+    /// users, each with a `token`, an optional `label`, and optionally the
+    /// `providers` it shows and its own `title_settings` and `title_rules`.
+    fn read_discord_users(root: &Section) -> Result<Vec<DiscordUserConfig>, ConfigError> {
+        let sections = root.objects("discord")?;
+        if let Some(token) = root.string("discord_token")? {
+            if !sections.is_empty() {
+                return Err(root.invalid("discord", "cannot be combined with discord_token"));
+            }
+
+            return Ok(vec![DiscordUserConfig {
+                               label: "default".to_owned(),
+                               token: token.to_owned(),
+                               providers: Vec::new(),
+                               title_rules: TitleRules::default(),
+                           }]);
+        }
+
+        if sections.is_empty() {
+            return Err(ConfigError::MissingKey("discord_token".to_owned()));
+        }
+
+        let mut users = Vec::new();
+        let mut labels = Vec::new();
+        for (i, section) in sections.iter().enumerate() {
+            section.check_keys(DISCORD_USER_KEYS)?;
+            let providers = section.string_array("providers")?.unwrap_or(Vec::new());
+            check_provider_refs(&section.key_path("providers"), &providers)?;
+
+            users.push(DiscordUserConfig {
+                label: read_label(section, &format!("user{}", i + 1), &mut labels)?,
+                token: section.required_string("token")?.to_owned(),
+                providers: providers,
+                title_rules: TitleRules::from_config(section)?,
+            });
         }
 
-        Ok(PresenceMonitorConfig {
-            discord_token: config.discord_token.clone(),
-            update_interval: Duration::from_secs(config.update_interval.unwrap_or(30u64)),
-            title_settings: title_settings,
-            json: json,
-        })
+        Ok(users)
     }
 
-    fn convert_title_setting(string: &str) -> TitleSetting {
-        match string {
-            "ignore" => TitleSetting::Ignore,
-            "name-only" => TitleSetting::NameOnly,
-            _ => TitleSetting::Full,
+    /// Checks that every "name:label" key in `provider_priority` and the users'
+    /// `providers` is one of `accounts`, the keys of the configured provider
+    /// accounts. Labels default to the account IDs, so this is only possible
+    /// once the provider sections are parsed.
+    pub fn check_account_refs(&self, accounts: &[String]) -> Result<(), ConfigError> {
+        let root = Section::root(&self.json);
+        check_labels(&root.key_path("provider_priority"),
+                     &self.provider_priority,
+                     accounts)?;
+        for (section, user) in root.objects("discord")?.iter().zip(&self.discord_users) {
+            check_labels(&section.key_path("providers"), &user.providers, accounts)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn discord_user(&self, label: &str) -> Option<&DiscordUserConfig> {
+        self.discord_users.iter().find(|x| x.label == label)
+    }
+
+    fn parse_template(path: &str, format: &str) -> Result<Template, ConfigError> {
+        format.parse::<Template>().map_err(|e| ConfigError::Template(path.to_owned(), e))
+    }
+}
+
+/// Checks a list of provider names or "name:label" keys.
+fn check_provider_refs(path: &str, refs: &[String]) -> Result<(), ConfigError> {
+    for (i, name) in refs.iter().enumerate() {
+        let provider = name.split(':').next().unwrap();
+        if !PROVIDER_NAMES.contains(&provider) {
+            let reason = format!("unknown provider '{}', expected one of: {}",
+                                 name,
+                                 PROVIDER_NAMES.join(", "));
+            return Err(ConfigError::InvalidValue(format!("{}[{}]", path, i), reason));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the "name:label" keys among `refs` name one of `accounts`.
+fn check_labels(path: &str, refs: &[String], accounts: &[String]) -> Result<(), ConfigError> {
+    for (i, key) in refs.iter().enumerate() {
+        if key.contains(':') && !accounts.contains(key) {
+            let reason = format!("unknown account '{}', expected one of: {}",
+                                 key,
+                                 accounts.join(", "));
+            return Err(ConfigError::InvalidValue(format!("{}[{}]", path, i), reason));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Lists the top level keys that differ between two configs. Values are left
+/// out since several of them are credentials.
+pub fn changed_keys(old: &HJsonObject, new: &HJsonObject) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (key, value) in old.iter() {
+        match new.get(key) {
+            None => changes.push(format!("{} removed", key)),
+            Some(v) if v != value => changes.push(format!("{} changed", key)),
+            Some(_) => {}
+        }
+    }
+
+    for key in new.keys().filter(|x| !old.contains_key(*x)) {
+        changes.push(format!("{} added", key));
+    }
+
+    changes
+}
+
+/// The section of every provider account, keyed like the provider instance
+/// ("name:label", with the label defaulting to the `id`). Providers without
+/// accounts, like `dummy`, are keyed by their name.
+pub fn account_sections(json: &HJsonObject) -> HashMap<String, &HJsonValue> {
+    let mut sections = HashMap::new();
+    for name in PROVIDER_NAMES {
+        let accounts = match json.get(*name) {
+            Some(&HJsonValue::Array(ref a)) => a.iter().collect(),
+            Some(v) => vec![v],
+            None => Vec::new(),
+        };
+
+        for account in accounts {
+            let label = account.find("label")
+                .or_else(|| account.find("id"))
+                .and_then(|x| x.as_str());
+            let key = match label {
+                Some(label) => format!("{}:{}", name, label),
+                None => name.to_string(),
+            };
+            sections.insert(key, account);
         }
     }
+
+    sections
+}
+
+/// Reads the optional `label` of a provider account, which defaults to the
+/// account ID and must be unique among the accounts of one provider. Labels
+/// name the record and replay directories, so only letters, digits, `-` and
+/// `_` are allowed.
+pub fn read_label(section: &Section,
+                  id: &str,
+                  seen: &mut Vec<String>)
+                  -> Result<String, ConfigError> {
+    let label = section.string("label")?.unwrap_or(id).to_owned();
+    if label.is_empty() {
+        return Err(section.invalid("label", "must not be empty"));
+    }
+
+    let plain = label.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => false,
+    });
+    if !plain {
+        let reason = format!("'{}' may only contain letters, digits, '-' and '_'", label);
+        return Err(section.invalid("label", &reason));
+    }
+
+    if seen.contains(&label) {
+        return Err(section.invalid("label", &format!("'{}' is used by another account", label)));
+    }
+
+    seen.push(label.clone());
+    Ok(label)
+}
+
+/// Reads the optional `endpoints` object of a provider section. Every key must
+/// appear in `defaults`; missing keys fall back to the default URL. URLs are
+/// returned without a trailing slash so paths can be appended directly.
+pub fn read_endpoints(section: &Section,
+                      defaults: &[(&'static str, &'static str)])
+                      -> Result<HashMap<&'static str, String>, ConfigError> {
+    let mut endpoints: HashMap<&'static str, String> = defaults.iter()
+        .map(|&(key, url)| (key, url.to_owned()))
+        .collect();
+
+    let endpoints_section = match section.object("endpoints")? {
+        Some(s) => s,
+        None => return Ok(endpoints),
+    };
+
+    endpoints_section.check_keys(&defaults.iter().map(|x| x.0).collect::<Vec<_>>())?;
+    for &(key, _) in defaults {
+        if let Some(url) = endpoints_section.string(key)? {
+            let url = validate_base_url(url).map_err(|e| endpoints_section.invalid(key, &e))?;
+            endpoints.insert(key, url);
+        }
+    }
+
+    Ok(endpoints)
+}
+
+fn validate_base_url(url: &str) -> Result<String, String> {
+    let parsed = Url::parse(url).map_err(|e| e.to_string())?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!("unsupported scheme '{}'", parsed.scheme()));
+    }
+
+    if parsed.host_str().is_none() {
+        return Err("missing host".to_owned());
+    }
+
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err("base URLs cannot have a query or fragment".to_owned());
+    }
+
+    Ok(url.trim_right_matches('/').to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_hjson;
+
+    use super::*;
+
+    fn json(hjson: &str) -> HJsonObject {
+        match serde_hjson::from_str(hjson).unwrap() {
+            HJsonValue::Object(o) => o,
+            _ => panic!("expected an object"),
+        }
+    }
+
+    #[test]
+    fn lists_changed_keys() {
+        let old = json(r#"{"update_interval": 30, "xbl": {"id": "1"}, "dummy": {}}"#);
+        let new = json(r#"{"update_interval": 60, "xbl": {"id": "1"}, "psn": {"id": "2"}}"#);
+        let mut changes = changed_keys(&old, &new);
+        changes.sort();
+        assert_eq!(changes, vec!["dummy removed", "psn added", "update_interval changed"]);
+        assert!(changed_keys(&old, &old).is_empty());
+    }
+
+    fn accounts() -> Vec<String> {
+        vec!["psn:alice".to_owned(), "xbl:2533274800000000".to_owned(), "dummy".to_owned()]
+    }
+
+    fn check_account_refs(hjson: &str) -> Result<(), ConfigError> {
+        PresenceMonitorConfig::from_json(json(hjson)).unwrap().check_account_refs(&accounts())
+    }
+
+    #[test]
+    fn accepts_known_accounts() {
+        check_account_refs(r#"{"discord": [{"token": "a", "providers": ["psn:alice", "xbl"]},
+                                           {"token": "b", "providers": ["dummy"]}],
+                               "provider_priority": ["xbl:2533274800000000", "psn"]}"#)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_account_in_user_providers() {
+        let err = check_account_refs(r#"{"discord": [{"token": "a", "providers": ["psn"]},
+                                                     {"token": "b",
+                                                      "providers": ["xbl", "psn:alcie"]}]}"#)
+            .unwrap_err();
+        match err {
+            ConfigError::InvalidValue(path, reason) => {
+                assert_eq!(path, "discord[1].providers[1]");
+                assert!(reason.contains("'psn:alcie'"));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_account_in_provider_priority() {
+        let err = check_account_refs(r#"{"discord_token": "a",
+                                         "provider_priority": ["psn:alice", "xbl:alice"]}"#)
+            .unwrap_err();
+        match err {
+            ConfigError::InvalidValue(path, _) => assert_eq!(path, "provider_priority[1]"),
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn label(hjson: &str) -> Result<String, ConfigError> {
+        let json = json(hjson);
+        read_label(&Section::root(&json), "someone", &mut Vec::new())
+    }
+
+    #[test]
+    fn labels_default_to_the_id() {
+        assert_eq!(label("{}").unwrap(), "someone");
+        assert_eq!(label(r#"{"label": "Main_PS4-2"}"#).unwrap(), "Main_PS4-2");
+    }
+
+    #[test]
+    fn rejects_labels_that_are_not_plain_names() {
+        for bad in &["", "..", "../escape", "a/b", r"a\b", "a b", "a:b"] {
+            let hjson = format!(r#"{{"label": "{}"}}"#, bad);
+            match label(&hjson).unwrap_err() {
+                ConfigError::InvalidValue(path, _) => assert_eq!(path, "label"),
+                e => panic!("unexpected error for {:?}: {}", bad, e),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        let json = json(r#"{"label": "main"}"#);
+        let mut seen = vec!["main".to_owned()];
+        assert!(read_label(&Section::root(&json), "someone", &mut seen).is_err());
+    }
+
+    #[test]
+    fn account_sections_are_keyed_by_label() {
+        let config = json(r#"{"psn": [{"id": "someone", "refresh_token": "a"},
+                                      {"id": "other", "label": "alt", "refresh_token": "b"}],
+                              "xbl": {"id": "2533274800000000"},
+                              "dummy": true}"#);
+        let sections = account_sections(&config);
+        let mut keys = sections.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["dummy", "psn:alt", "psn:someone", "xbl:2533274800000000"]);
+        assert_eq!(sections["psn:alt"].find("refresh_token").and_then(|x| x.as_str()),
+                   Some("b"));
+    }
+
+    #[test]
+    fn editing_one_account_changes_only_its_section() {
+        let old = json(r#"{"psn": [{"id": "a", "refresh_token": "1"},
+                                   {"id": "b", "refresh_token": "2"}]}"#);
+        let new = json(r#"{"psn": [{"id": "a", "refresh_token": "1"},
+                                   {"id": "b", "refresh_token": "3"}]}"#);
+        let (old, new) = (account_sections(&old), account_sections(&new));
+        assert_eq!(old.get("psn:a"), new.get("psn:a"));
+        assert!(old.get("psn:b") != new.get("psn:b"));
+    }
 }
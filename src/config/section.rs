@@ -0,0 +1,158 @@
+use serde_hjson::Value as HJsonValue;
+use std::time::Duration;
+
+use HJsonObject;
+use super::ConfigError;
+
+/// An object in the config file along with its key path, so every error can
+/// point at the exact key that is wrong.
+pub struct Section<'a> {
+    obj: &'a HJsonObject,
+    path: String,
+}
+
+impl<'a> Section<'a> {
+    pub fn root(obj: &'a HJsonObject) -> Section<'a> {
+        Section::new(obj, String::new())
+    }
+
+    pub fn new(obj: &'a HJsonObject, path: String) -> Section<'a> {
+        Section {
+            obj: obj,
+            path: path,
+        }
+    }
+
+    pub fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    pub fn obj(&self) -> &'a HJsonObject {
+        self.obj
+    }
+
+    pub fn check_keys(&self, allowed: &[&str]) -> Result<(), ConfigError> {
+        for key in self.obj.keys() {
+            if !allowed.contains(&key.as_str()) {
+                return Err(ConfigError::UnknownKey(self.key_path(key), allowed.join(", ")));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn invalid(&self, key: &str, reason: &str) -> ConfigError {
+        ConfigError::InvalidValue(self.key_path(key), reason.to_owned())
+    }
+
+    pub fn object(&self, key: &str) -> Result<Option<Section<'a>>, ConfigError> {
+        match self.obj.get(key) {
+            None => Ok(None),
+            Some(&HJsonValue::Object(ref o)) => Ok(Some(Section::new(o, self.key_path(key)))),
+            Some(_) => Err(self.invalid(key, "expected an object")),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<&'a str>, ConfigError> {
+        match self.obj.get(key) {
+            None => Ok(None),
+            Some(&HJsonValue::String(ref s)) => Ok(Some(s.as_str())),
+            Some(_) => Err(self.invalid(key, "expected a string")),
+        }
+    }
+
+    pub fn required_string(&self, key: &str) -> Result<&'a str, ConfigError> {
+        match self.string(key)? {
+            Some(s) => Ok(s),
+            None => Err(ConfigError::MissingKey(self.key_path(key))),
+        }
+    }
+
+    pub fn bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.obj.get(key) {
+            None => Ok(None),
+            Some(&HJsonValue::Bool(b)) => Ok(Some(b)),
+            Some(_) => Err(self.invalid(key, "expected true or false")),
+        }
+    }
+
+    /// HJSON parses every number as a float, so integers are accepted from
+    /// any numeric value without a fractional part.
+    pub fn u64(&self, key: &str) -> Result<Option<u64>, ConfigError> {
+        match self.obj.get(key) {
+            None => Ok(None),
+            Some(&HJsonValue::U64(n)) => Ok(Some(n)),
+            Some(&HJsonValue::I64(n)) if n >= 0 => Ok(Some(n as u64)),
+            Some(&HJsonValue::F64(n)) if n >= 0.0 && n.fract() == 0.0 => Ok(Some(n as u64)),
+            Some(_) => Err(self.invalid(key, "expected a non-negative whole number")),
+        }
+    }
+
+    /// A number of seconds, which must be at least 1.
+    pub fn duration_secs(&self, key: &str, default: Duration) -> Result<Duration, ConfigError> {
+        match self.u64(key)? {
+            Some(0) => Err(self.invalid(key, "must be at least 1")),
+            Some(n) => Ok(Duration::from_secs(n)),
+            None => Ok(default),
+        }
+    }
+
+    pub fn array(&self, key: &str) -> Result<Option<&'a Vec<HJsonValue>>, ConfigError> {
+        match self.obj.get(key) {
+            None => Ok(None),
+            Some(&HJsonValue::Array(ref a)) => Ok(Some(a)),
+            Some(_) => Err(self.invalid(key, "expected an array")),
+        }
+    }
+
+    pub fn string_array(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        let array = match self.array(key)? {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+
+        let mut result = Vec::new();
+        for (i, value) in array.iter().enumerate() {
+            match *value {
+                HJsonValue::String(ref s) => result.push(s.clone()),
+                _ => {
+                    return Err(ConfigError::InvalidValue(format!("{}[{}]", self.key_path(key), i),
+                                                         "expected a string".to_owned()))
+                }
+            }
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Iterates the objects of an array, each as its own section.
+    pub fn object_array(&self, key: &str) -> Result<Vec<Section<'a>>, ConfigError> {
+        let array = match self.array(key)? {
+            Some(a) => a,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::new();
+        for (i, value) in array.iter().enumerate() {
+            let path = format!("{}[{}]", self.key_path(key), i);
+            match *value {
+                HJsonValue::Object(ref o) => result.push(Section::new(o, path)),
+                _ => return Err(ConfigError::InvalidValue(path, "expected an object".to_owned())),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Reads a key that may hold either a single object or an array of them.
+    pub fn objects(&self, key: &str) -> Result<Vec<Section<'a>>, ConfigError> {
+        match self.obj.get(key) {
+            Some(&HJsonValue::Array(_)) => self.object_array(key),
+            _ => Ok(self.object(key)?.into_iter().collect()),
+        }
+    }
+}
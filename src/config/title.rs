@@ -0,0 +1,269 @@
+use regex::{self, Regex};
+use serde_hjson::Value as HJsonValue;
+
+use template::Template;
+use super::{ConfigError, PresenceMonitorConfig, Section, TitleSetting};
+
+const TITLE_CONFIG_KEYS: &'static [&'static str] = &["setting", "format", "display_name"];
+const TITLE_RULE_KEYS: &'static [&'static str] =
+    &["match", "type", "case_insensitive", "setting", "format", "display_name"];
+
+pub struct TitleConfig {
+    pub setting: TitleSetting,
+    pub format: Option<Template>,
+    pub display_name: Option<String>,
+}
+
+enum Pattern {
+    Exact(String),
+    ExactCaseInsensitive(String),
+    Regex(Regex),
+    TitleId(String),
+}
+
+impl Pattern {
+    fn is_match(&self, title: &str, title_id: Option<&str>) -> bool {
+        match *self {
+            Pattern::Exact(ref s) => s == title,
+            Pattern::ExactCaseInsensitive(ref s) => *s == title.to_lowercase(),
+            Pattern::Regex(ref r) => r.is_match(title),
+            Pattern::TitleId(ref s) => title_id.map_or(false, |x| *s == x.to_lowercase()),
+        }
+    }
+}
+
+struct TitleRule {
+    pattern: Pattern,
+    config: TitleConfig,
+}
+
+/// Title settings in match order: the exact names from `title_settings`
+/// first, then the `title_rules` list in the order it was written. The first
+/// matching rule wins.
+#[derive(Default)]
+pub struct TitleRules {
+    rules: Vec<TitleRule>,
+}
+
+impl TitleRules {
+    pub fn from_config(root: &Section) -> Result<TitleRules, ConfigError> {
+        let mut rules = Vec::new();
+
+        if let Some(section) = root.object("title_settings")? {
+            for (title, value) in section.obj().iter() {
+                let path = section.key_path(title);
+                let config = match *value {
+                    HJsonValue::String(ref s) => {
+                        TitleConfig {
+                            setting: convert_title_setting(&path, s)?,
+                            format: None,
+                            display_name: None,
+                        }
+                    }
+                    HJsonValue::Object(ref o) => {
+                        let title_section = Section::new(o, path);
+                        title_section.check_keys(TITLE_CONFIG_KEYS)?;
+                        convert_title_config(&title_section)?
+                    }
+                    _ => {
+                        return Err(ConfigError::InvalidValue(path,
+                                                             "expected a title setting name or \
+                                                              an object"
+                                                                 .to_owned()))
+                    }
+                };
+
+                rules.push(TitleRule {
+                    pattern: Pattern::Exact(title.clone()),
+                    config: config,
+                });
+            }
+        }
+
+        for section in root.object_array("title_rules")? {
+            section.check_keys(TITLE_RULE_KEYS)?;
+            rules.push(TitleRule {
+                pattern: convert_pattern(&section)?,
+                config: convert_title_config(&section)?,
+            });
+        }
+
+        Ok(TitleRules { rules: rules })
+    }
+
+    /// A rule matches if it matches either the title as reported or its
+    /// normalized form, so rule order decides and not which name matched.
+    /// `title_id` is the platform's title ID if the provider knows it, which
+    /// is what rules of type `id` match against.
+    pub fn find(&self,
+                title: &str,
+                normalized: &str,
+                title_id: Option<&str>)
+                -> Option<&TitleConfig> {
+        self.rules
+            .iter()
+            .find(|x| {
+                x.pattern.is_match(title, title_id) || x.pattern.is_match(normalized, title_id)
+            })
+            .map(|x| &x.config)
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut result = "^".to_owned();
+    for c in glob.chars() {
+        match c {
+            '*' => result.push_str(".*"),
+            '?' => result.push('.'),
+            _ => result.push_str(&regex::quote(&c.to_string())),
+        }
+    }
+    result.push('$');
+    result
+}
+
+/// Reads the `match`, `type` and `case_insensitive` keys of a `title_rules`
+/// entry. `type` is one of `exact` (the default), `glob`, `regex` or `id`. An
+/// `id` rule matches the platform's title ID (e.g. "CUSA00572_00" on PSN or
+/// "1144039928" on Xbox) rather than the name.
+fn convert_pattern(section: &Section) -> Result<Pattern, ConfigError> {
+    let pattern = section.required_string("match")?;
+    let case_insensitive = section.bool("case_insensitive")?.unwrap_or(false);
+
+    let regex = match section.string("type")? {
+        None | Some("exact") => None,
+        Some("glob") => Some(glob_to_regex(pattern)),
+        Some("regex") => Some(pattern.to_owned()),
+        Some("id") => return Ok(Pattern::TitleId(pattern.to_lowercase())),
+        Some(_) => {
+            return Err(section.invalid("type", "expected 'exact', 'glob', 'regex' or 'id'"))
+        }
+    };
+
+    match regex {
+        None if case_insensitive => Ok(Pattern::ExactCaseInsensitive(pattern.to_lowercase())),
+        None => Ok(Pattern::Exact(pattern.to_owned())),
+        Some(r) => {
+            let r = if case_insensitive { format!("(?i){}", r) } else { r };
+            Regex::new(&r)
+                .map(Pattern::Regex)
+                .map_err(|e| section.invalid("match", &e.to_string()))
+        }
+    }
+}
+
+/// Reads the `setting`, `format` and `display_name` keys shared by
+/// `title_settings` objects and `title_rules` entries.
+fn convert_title_config(section: &Section) -> Result<TitleConfig, ConfigError> {
+    let setting = match section.string("setting")? {
+        None => TitleSetting::Full,
+        Some(s) => convert_title_setting(&section.key_path("setting"), s)?,
+    };
+
+    let format = match section.string("format")? {
+        None => None,
+        Some(s) => Some(PresenceMonitorConfig::parse_template(&section.key_path("format"), s)?),
+    };
+
+    Ok(TitleConfig {
+        setting: setting,
+        format: format,
+        display_name: section.string("display_name")?.map(|x| x.to_owned()),
+    })
+}
+
+fn convert_title_setting(path: &str, string: &str) -> Result<TitleSetting, ConfigError> {
+    match string {
+        "ignore" => Ok(TitleSetting::Ignore),
+        "name-only" => Ok(TitleSetting::NameOnly),
+        "full" => Ok(TitleSetting::Full),
+        _ => {
+            Err(ConfigError::InvalidValue(path.to_owned(),
+                                          format!("unknown title setting '{}', expected \
+                                                   'ignore', 'name-only' or 'full'",
+                                                  string)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_hjson::{self, Value as HJsonValue};
+
+    use super::*;
+    use config::Section;
+
+    fn rules(hjson: &str) -> TitleRules {
+        match serde_hjson::from_str(hjson).unwrap() {
+            HJsonValue::Object(o) => TitleRules::from_config(&Section::root(&o)).unwrap(),
+            _ => panic!("expected an object"),
+        }
+    }
+
+    fn display_name(rules: &TitleRules, title: &str, normalized: &str) -> Option<String> {
+        rules.find(title, normalized, None).and_then(|x| x.display_name.clone())
+    }
+
+    #[test]
+    fn normalized_match_keeps_rule_order() {
+        let rules = rules(r#"{"title_rules": [
+            {"match": "Call of Duty WWII", "display_name": "first"},
+            {"match": "Call of Duty®*", "type": "glob", "display_name": "second"}]}"#);
+        assert_eq!(display_name(&rules, "Call of Duty® WWII", "Call of Duty WWII"),
+                   Some("first".to_owned()));
+        assert_eq!(display_name(&rules, "Call of Duty® Ghosts", "Call of Duty Ghosts"),
+                   Some("second".to_owned()));
+    }
+
+    #[test]
+    fn glob_to_regex_quotes_everything_else() {
+        assert_eq!(glob_to_regex("Halo*"), "^Halo.*$");
+        assert_eq!(glob_to_regex("Halo ?"), "^Halo .$");
+
+        let re = Regex::new(&glob_to_regex("F1 (201?) [*]")).unwrap();
+        assert!(re.is_match("F1 (2017) [PS4]"));
+        assert!(!re.is_match("F1 2017 PS4"));
+        assert!(!re.is_match("xF1 (2017) []"));
+    }
+
+    #[test]
+    fn case_insensitive_rules() {
+        let rules = rules(r#"{"title_rules": [
+            {"match": "NETFLIX", "case_insensitive": true, "display_name": "exact"},
+            {"match": "halo*", "type": "glob", "case_insensitive": true,
+             "display_name": "glob"},
+            {"match": "Destiny", "display_name": "sensitive"}]}"#);
+        assert_eq!(display_name(&rules, "Netflix", "Netflix"), Some("exact".to_owned()));
+        assert_eq!(display_name(&rules, "HALO 5", "HALO 5"), Some("glob".to_owned()));
+        assert_eq!(display_name(&rules, "DESTINY", "DESTINY"), None);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = rules(r#"{"title_rules": [
+            {"match": "^Halo", "type": "regex", "display_name": "first"},
+            {"match": "Halo 5", "display_name": "second"}]}"#);
+        assert_eq!(display_name(&rules, "Halo 5", "Halo 5"), Some("first".to_owned()));
+    }
+
+    #[test]
+    fn title_settings_come_before_title_rules() {
+        let rules = rules(r#"{
+            "title_rules": [{"match": "*", "type": "glob", "setting": "ignore"}],
+            "title_settings": {"Halo 5": "name-only"}}"#);
+        assert_eq!(rules.find("Halo 5", "Halo 5", None).unwrap().setting,
+                   TitleSetting::NameOnly);
+        assert_eq!(rules.find("Destiny", "Destiny", None).unwrap().setting,
+                   TitleSetting::Ignore);
+    }
+
+    #[test]
+    fn id_rules_match_the_title_id() {
+        let rules = rules(r#"{"title_rules": [
+            {"match": "CUSA00207_00", "type": "id", "display_name": "by id"}]}"#);
+        assert_eq!(rules.find("Bloodborne", "Bloodborne", Some("cusa00207_00"))
+                       .and_then(|x| x.display_name.clone()),
+                   Some("by id".to_owned()));
+        assert!(rules.find("CUSA00207_00", "CUSA00207_00", None).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &'static str = "discord_console_status";
+
+/// Picks the default location of a config file: `file_name` in the working
+/// directory if it exists there, otherwise inside
+/// `$XDG_CONFIG_HOME/discord_console_status` (`~/.config` when unset).
+pub fn default_path(file_name: &str) -> String {
+    default_in(file_name, xdg_dir("XDG_CONFIG_HOME", ".config"))
+}
+
+/// Like `default_path`, for files the program writes itself. These go in
+/// `$XDG_STATE_HOME/discord_console_status` (`~/.local/state` when unset).
+pub fn default_state_path(file_name: &str) -> String {
+    default_in(file_name, xdg_dir("XDG_STATE_HOME", ".local/state"))
+}
+
+fn default_in(file_name: &str, dir: Option<PathBuf>) -> String {
+    if Path::new(file_name).exists() {
+        return file_name.to_owned();
+    }
+
+    match dir {
+        Some(dir) => dir.join(APP_DIR).join(file_name).to_string_lossy().into_owned(),
+        None => file_name.to_owned(),
+    }
+}
+
+/// `$var` if it holds an absolute path, otherwise `fallback` in the home
+/// directory.
+#[cfg(unix)]
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+        Some(ref dir) if Path::new(dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => env::home_dir().map(|x| x.join(fallback)),
+    }
+}
+
+#[cfg(not(unix))]
+fn xdg_dir(_: &str, _: &str) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(unix)]
+mod detail {
+    extern crate nix;
+
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+    use self::nix::unistd::{self, ForkResult};
+
+    pub fn pid() -> i32 {
+        unistd::getpid()
+    }
+
+    pub fn daemonize() -> io::Result<()> {
+        if let ForkResult::Parent { .. } = unistd::fork()? {
+            process::exit(0);
+        }
+
+        if unsafe { nix::libc::setsid() } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        for fd in 0..3 {
+            unistd::dup2(null.as_raw_fd(), fd)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod detail {
+    extern crate kernel32;
+
+    use std::io;
+
+    pub fn pid() -> i32 {
+        unsafe { kernel32::GetCurrentProcessId() as i32 }
+    }
+
+    pub fn daemonize() -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "running in the background needs unix"))
+    }
+}
+
+/// Detaches from the terminal by forking and starting a new session. The
+/// parent exits, and stdin, stdout and stderr of the child point to
+/// /dev/null. Must be called before any threads are started.
+pub fn daemonize() -> io::Result<()> {
+    detail::daemonize()
+}
+
+/// Holds the process ID in a file for as long as it is alive.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<PidFile> {
+        let path = path.into();
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", detail::pid())?;
+        Ok(PidFile { path: path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use serde_json::builder::ObjectBuilder;
+use websocket::client::{self, Client};
+use websocket::client::request::Url;
+use websocket::message::{Message, Type};
+use websocket::result::WebSocketError;
+use websocket::stream::WebSocketStream;
+use websocket::ws::receiver::Receiver;
+use websocket::ws::sender::Sender;
+
+pub const DEFAULT_URL: &'static str = "wss://gateway.discord.gg";
+const VERSION: u64 = 6;
+/// A write that blocks this long means the connection is dead.
+const WRITE_TIMEOUT_SECS: u64 = 10;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum GatewayError {
+        WebSocket(err: WebSocketError) {
+            from()
+            description("websocket error")
+            display("Websocket error: {}", err)
+            cause(err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            description("json parse error")
+            display("JSON parsing error: {}", err)
+            cause(err)
+        }
+        Closed(code: Option<u16>, reason: String) {
+            description("connection closed")
+            display("Discord closed the connection ({}): {}",
+                    code.map_or("no code".to_owned(), |x| x.to_string()),
+                    reason)
+        }
+        Protocol(msg: &'static str) {
+            description("unexpected gateway message")
+            display("Unexpected gateway message: {}", msg)
+        }
+    }
+}
+
+/// The gateway messages a session cares about. Dispatches are left undecoded
+/// apart from their sequence number and event name.
+pub enum GatewayEvent {
+    Hello(Duration),
+    Dispatch(u64, String, Value),
+    Heartbeat,
+    HeartbeatAck,
+    Reconnect,
+    InvalidSession,
+}
+
+impl GatewayEvent {
+    fn decode(value: Value) -> Result<GatewayEvent, GatewayError> {
+        match value.find("op").and_then(Value::as_u64) {
+            Some(0) => {
+                let sequence = value.find("s")
+                    .and_then(Value::as_u64)
+                    .ok_or(GatewayError::Protocol("dispatch without sequence number"))?;
+                let name = value.find("t")
+                    .and_then(Value::as_str)
+                    .ok_or(GatewayError::Protocol("dispatch without event name"))?;
+                let data = value.find("d").cloned().unwrap_or(Value::Null);
+                Ok(GatewayEvent::Dispatch(sequence, name.to_owned(), data))
+            }
+            Some(1) => Ok(GatewayEvent::Heartbeat),
+            Some(7) => Ok(GatewayEvent::Reconnect),
+            Some(9) => Ok(GatewayEvent::InvalidSession),
+            Some(10) => {
+                value.lookup("d.heartbeat_interval")
+                    .and_then(Value::as_u64)
+                    .map(|x| GatewayEvent::Hello(Duration::from_millis(x)))
+                    .ok_or(GatewayError::Protocol("hello without heartbeat interval"))
+            }
+            Some(11) => Ok(GatewayEvent::HeartbeatAck),
+            _ => Err(GatewayError::Protocol("unknown opcode")),
+        }
+    }
+}
+
+/// The receiving half of a gateway connection.
+pub struct GatewayReader {
+    receiver: client::Receiver<WebSocketStream>,
+}
+
+impl GatewayReader {
+    /// Blocks until the next message arrives or the connection is shut down.
+    pub fn recv(&mut self) -> Result<GatewayEvent, GatewayError> {
+        loop {
+            let message: Message = self.receiver.recv_message()?;
+            match message.opcode {
+                Type::Text => {
+                    let value = serde_json::from_slice(&message.payload)?;
+                    return GatewayEvent::decode(value);
+                }
+                Type::Close => {
+                    let reason = String::from_utf8_lossy(&message.payload).into_owned();
+                    return Err(GatewayError::Closed(message.cd_status_code, reason));
+                }
+                // compression is never asked for, and Discord doesn't ping
+                _ => debug!("Ignoring {:?} gateway frame", message.opcode),
+            }
+        }
+    }
+}
+
+/// The sending half of a gateway connection. Unlike the discord crate's
+/// `Connection`, sending doesn't wait for the receiving half to see traffic.
+pub struct GatewayWriter {
+    sender: client::Sender<WebSocketStream>,
+}
+
+impl GatewayWriter {
+    fn send(&mut self, value: Value) -> Result<(), GatewayError> {
+        let message = Message::text(serde_json::to_string(&value)?);
+        Ok(self.sender.send_message(&message)?)
+    }
+
+    pub fn identify(&mut self, token: &str) -> Result<(), GatewayError> {
+        self.send(ObjectBuilder::new()
+            .insert("op", 2)
+            .insert_object("d", |o| {
+                o.insert("token", token)
+                    .insert_object("properties", |o| {
+                        o.insert("$os", ::std::env::consts::OS)
+                            .insert("$browser", "discord_console_status")
+                            .insert("$device", "discord_console_status")
+                    })
+                    .insert("compress", false)
+                    .insert("large_threshold", 50)
+            })
+            .build())
+    }
+
+    pub fn resume(&mut self,
+                  token: &str,
+                  session_id: &str,
+                  sequence: u64)
+                  -> Result<(), GatewayError> {
+        self.send(ObjectBuilder::new()
+            .insert("op", 6)
+            .insert_object("d", |o| {
+                o.insert("token", token)
+                    .insert("session_id", session_id)
+                    .insert("seq", sequence)
+            })
+            .build())
+    }
+
+    pub fn heartbeat(&mut self, sequence: Option<u64>) -> Result<(), GatewayError> {
+        self.send(ObjectBuilder::new()
+            .insert("op", 1)
+            .insert("d", sequence)
+            .build())
+    }
+
+    /// Shows `game` as the game being played, or nothing.
+    pub fn set_game(&mut self, game: Option<&str>) -> Result<(), GatewayError> {
+        self.send(ObjectBuilder::new()
+            .insert("op", 3)
+            .insert_object("d", |o| {
+                let o = o.insert("afk", false)
+                    .insert("since", 0)
+                    .insert("status", "online");
+                match game {
+                    Some(name) => o.insert_object("game", |g| g.insert("name", name)),
+                    None => o.insert("game", Value::Null),
+                }
+            })
+            .build())
+    }
+
+    /// Shuts the socket down, which also ends a `GatewayReader::recv` that is
+    /// waiting on it. Sends a close frame first if `clean`.
+    pub fn close(&mut self, clean: bool) {
+        if clean {
+            let _ = self.sender.send_message(&Message::close_because(1000, ""));
+        }
+        let _ = self.sender.shutdown_all();
+    }
+}
+
+/// Opens a gateway connection. Nothing is sent yet, the first message to
+/// expect is `GatewayEvent::Hello`.
+pub fn connect(url: &str) -> Result<(GatewayWriter, GatewayReader), GatewayError> {
+    let url = Url::parse(&format!("{}/?v={}&encoding=json", url, VERSION))
+        .map_err(WebSocketError::from)?;
+    let response = Client::connect(url)?.send()?;
+    response.validate()?;
+    let (sender, receiver) = response.begin().split();
+
+    // both halves share the socket, so this covers heartbeats and status updates
+    let timeout = Some(Duration::from_secs(WRITE_TIMEOUT_SECS));
+    let result = match *sender.get_ref() {
+        WebSocketStream::Tcp(ref s) => s.set_write_timeout(timeout),
+        WebSocketStream::Ssl(ref s) => s.get_ref().set_write_timeout(timeout),
+    };
+    result.map_err(WebSocketError::IoError)?;
+
+    Ok((GatewayWriter { sender: sender }, GatewayReader { receiver: receiver }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn decode(json: &str) -> Result<GatewayEvent, GatewayError> {
+        GatewayEvent::decode(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn decodes_hello() {
+        match decode(r#"{"op": 10, "d": {"heartbeat_interval": 41250}}"#) {
+            Ok(GatewayEvent::Hello(interval)) => {
+                assert_eq!(interval, Duration::from_millis(41250))
+            }
+            _ => panic!("expected hello"),
+        }
+    }
+
+    #[test]
+    fn decodes_dispatch() {
+        match decode(r#"{"op": 0, "s": 3, "t": "READY", "d": {"session_id": "abc"}}"#) {
+            Ok(GatewayEvent::Dispatch(3, ref name, ref data)) if name == "READY" => {
+                assert_eq!(data.find("session_id").and_then(Value::as_str), Some("abc"));
+            }
+            _ => panic!("expected dispatch"),
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_messages() {
+        assert!(decode(r#"{"op": 0, "t": "READY", "d": {}}"#).is_err());
+        assert!(decode(r#"{"op": 10, "d": {}}"#).is_err());
+        assert!(decode(r#"{"op": 42}"#).is_err());
+        assert!(decode(r#"{"d": null}"#).is_err());
+    }
+}
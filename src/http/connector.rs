@@ -0,0 +1,199 @@
+use hyper::http::h1::Http11Protocol;
+use hyper::http::message::{HttpMessage, Protocol};
+use hyper::net::{HttpStream, HttpsStream, NetworkConnector, SslClient};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use hyper;
+
+/// The longest proxy response to a CONNECT that is accepted.
+const MAX_TUNNEL_RESPONSE: usize = 8192;
+
+/// Opens the connections of one client, directly or through an HTTP proxy.
+/// Does the same as hyper's `HttpsConnector` and proxy support, except that
+/// connecting, tunnelling and the TLS handshake give up after `timeout`.
+///
+/// Every connection opened is added to `connections`. hyper's pool only
+/// connects when it has no idle connection to the host, so any other request
+/// reused one.
+#[derive(Clone)]
+pub struct Connector<S> {
+    ssl: S,
+    timeout: Duration,
+    proxy: Option<(String, u16)>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl<S: SslClient> Connector<S> {
+    pub fn new(ssl: S,
+               timeout: Duration,
+               proxy: Option<(String, u16)>,
+               connections: Arc<AtomicUsize>)
+               -> Connector<S> {
+        Connector {
+            ssl: ssl,
+            timeout: timeout,
+            proxy: proxy,
+            connections: connections,
+        }
+    }
+}
+
+impl<S: SslClient> NetworkConnector for Connector<S> {
+    type Stream = HttpsStream<S::Stream>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        if scheme != "http" && scheme != "https" {
+            return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "Invalid scheme for Http")));
+        }
+
+        let stream = match self.proxy {
+            Some((ref proxy_host, proxy_port)) => {
+                let mut stream = connect_timeout(proxy_host, proxy_port, self.timeout)?;
+                if scheme == "https" {
+                    tunnel(&mut stream, host, port)?;
+                }
+                stream
+            }
+            None => connect_timeout(host, port, self.timeout)?,
+        };
+
+        let stream = if scheme == "https" {
+            HttpsStream::Https(self.ssl.wrap_client(HttpStream(stream), host)?)
+        } else {
+            HttpsStream::Http(HttpStream(stream))
+        };
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Ok(stream)
+    }
+}
+
+/// `TcpStream::connect` on a helper thread, so a host that never answers
+/// fails after `timeout` instead of the operating system's SYN timeout. A
+/// connection that completes later is dropped by the thread. The timeout is
+/// also set for reads and writes until hyper sets its own for the request.
+fn connect_timeout(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let addr = (host.to_owned(), port);
+    thread::Builder::new().name("http connect".to_owned())
+        .spawn(move || {
+            let _ = sender.send(TcpStream::connect((&addr.0[..], addr.1)));
+        })?;
+
+    let stream = match receiver.recv_timeout(timeout) {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("Connecting to {}:{} timed out", host, port)))
+        }
+    };
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Asks the proxy on the other end of `stream` to connect it to `host`.
+fn tunnel(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    write!(stream,
+           "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n",
+           host = host,
+           port = port)?;
+    stream.flush()?;
+
+    // one byte at a time, so nothing after the response headers is consumed
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_TUNNEL_RESPONSE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Proxy response too long"));
+        }
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Proxy closed the connection"));
+        }
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1).and_then(|x| x.parse::<u16>().ok()) {
+        Some(code) if code >= 200 && code < 300 => Ok(()),
+        _ => {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("Proxy refused to connect to {}:{}: {}",
+                                       host,
+                                       port,
+                                       status_line)))
+        }
+    }
+}
+
+/// hyper only sends the absolute URIs a proxy needs for plain HTTP requests
+/// when it set up the proxy itself, so this marks those messages as proxied.
+pub struct ProxiedProtocol(pub Http11Protocol);
+
+impl Protocol for ProxiedProtocol {
+    fn new_message(&self,
+                   host: &str,
+                   port: u16,
+                   scheme: &str)
+                   -> hyper::Result<Box<HttpMessage>> {
+        let mut message = self.0.new_message(host, port, scheme)?;
+        if scheme == "http" {
+            message.set_proxied(true);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn proxy(response: &'static str) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn tunnels_through_proxy() {
+        let (port, handle) = proxy("HTTP/1.1 200 Connection established\r\n\r\n");
+        let mut stream = connect_timeout("127.0.0.1", port, Duration::from_secs(5)).unwrap();
+        tunnel(&mut stream, "example.com", 443).unwrap();
+        assert_eq!(handle.join().unwrap(),
+                   "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+    }
+
+    #[test]
+    fn reports_refused_tunnel() {
+        let (port, handle) = proxy("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+        let mut stream = connect_timeout("127.0.0.1", port, Duration::from_secs(5)).unwrap();
+        let err = tunnel(&mut stream, "example.com", 443).unwrap_err();
+        assert!(err.to_string().contains("403 Forbidden"));
+        handle.join().unwrap();
+    }
+}
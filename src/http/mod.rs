@@ -0,0 +1,308 @@
+mod connector;
+mod replay;
+mod settings;
+
+pub use self::replay::{RecordingTransport, ReplayTransport};
+#[cfg(test)]
+pub use self::replay::testing;
+pub use self::settings::HttpSettings;
+
+use hyper::client::{Client as HttpClient, RedirectPolicy};
+use hyper::client::pool::Pool;
+use hyper::header::{Headers, UserAgent};
+use hyper::http::h1::Http11Protocol;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hyper;
+use self::connector::{Connector, ProxiedProtocol};
+
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Headers,
+    pub body: Option<String>,
+}
+
+impl HttpRequest {
+    pub fn get(url: &str, headers: Headers) -> HttpRequest {
+        HttpRequest {
+            method: Method::Get,
+            url: url.to_owned(),
+            headers: headers,
+            body: None,
+        }
+    }
+
+    pub fn post(url: &str, headers: Headers, body: String) -> HttpRequest {
+        HttpRequest {
+            method: Method::Post,
+            url: url.to_owned(),
+            headers: headers,
+            body: Some(body),
+        }
+    }
+}
+
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: String,
+}
+
+/// Sends requests on behalf of the providers. Everything the providers know
+/// about the network goes through this trait so they can be pointed at
+/// recorded fixtures instead of the real services.
+pub trait HttpTransport: Send {
+    fn send(&mut self, request: HttpRequest) -> hyper::Result<HttpResponse>;
+}
+
+/// Errors from a connection the server closed while it sat idle in the pool.
+fn is_stale_connection(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    match *method {
+        Method::Get | Method::Head => true,
+        _ => false,
+    }
+}
+
+/// Owns one hyper client for the lifetime of a provider, so connections are
+/// kept alive in its pool and reused across polls.
+pub struct HyperTransport {
+    name: String,
+    client: HttpClient,
+    user_agent: Option<String>,
+    requests: usize,
+    connections: Arc<AtomicUsize>,
+}
+
+impl HyperTransport {
+    /// The timeouts keep a hung service from blocking a provider thread, and
+    /// with it a clean shutdown, indefinitely.
+    pub fn new(name: &str,
+               redirect_policy: RedirectPolicy,
+               settings: &HttpSettings)
+               -> HyperTransport {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connector = Connector::new(settings.ssl.clone(),
+                                       settings.connect_timeout,
+                                       settings.proxy.clone(),
+                                       connections.clone());
+        let pool = Pool::with_connector(Default::default(), connector);
+        let mut client = match settings.proxy {
+            Some(_) => {
+                HttpClient::with_protocol(ProxiedProtocol(Http11Protocol::with_connector(pool)))
+            }
+            None => HttpClient::with_connector(pool),
+        };
+        client.set_redirect_policy(redirect_policy);
+        client.set_read_timeout(Some(settings.read_timeout));
+        client.set_write_timeout(Some(settings.write_timeout));
+        HyperTransport {
+            name: name.to_owned(),
+            client: client,
+            user_agent: settings.user_agent.clone(),
+            requests: 0,
+            connections: connections,
+        }
+    }
+
+    fn send_once(&self, request: &HttpRequest) -> hyper::Result<HttpResponse> {
+        let mut headers = request.headers.clone();
+        if let Some(ref user_agent) = self.user_agent {
+            headers.set(UserAgent(user_agent.clone()));
+        }
+
+        let mut req = self.client.request(request.method.clone(), &request.url).headers(headers);
+        if let Some(ref body) = request.body {
+            req = req.body(body);
+        }
+
+        let mut resp = req.send()?;
+        let mut body = String::new();
+        // reading to the end hands the connection back to the pool
+        resp.read_to_string(&mut body)?;
+
+        Ok(HttpResponse {
+            status: resp.status,
+            headers: resp.headers.clone(),
+            body: body,
+        })
+    }
+}
+
+impl HttpTransport for HyperTransport {
+    fn send(&mut self, request: HttpRequest) -> hyper::Result<HttpResponse> {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let response = match self.send_once(&request) {
+            // hyper hands out idle connections without checking them, so a
+            // server that dropped one between polls fails the next request.
+            // Only retried when no new connection was opened, i.e. one was
+            // reused. The same errors come from a server that closed after
+            // handling the request, so only requests that are safe to repeat
+            // are retried; a repeated token grant or login step is not.
+            Err(hyper::Error::Io(ref e))
+                if is_idempotent(&request.method) && is_stale_connection(e) &&
+                   self.connections.load(Ordering::Relaxed) == connections => {
+                debug!("{} - reused connection was closed ({}), retrying", self.name, e);
+                self.send_once(&request)?
+            }
+            result => result?,
+        };
+
+        self.requests += 1;
+        let connections = self.connections.load(Ordering::Relaxed);
+        debug!("{} - {} requests over {} connections ({} reused)",
+               self.name,
+               self.requests,
+               connections,
+               self.requests.saturating_sub(connections));
+
+        Ok(response)
+    }
+}
+
+#[derive(Clone)]
+pub enum TransportMode {
+    Live,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// Creates the transport for each provider. When recording or replaying, each
+/// transport gets its own subdirectory named after the provider.
+pub struct TransportFactory {
+    mode: TransportMode,
+    settings: HttpSettings,
+}
+
+impl TransportFactory {
+    pub fn new(mode: TransportMode) -> TransportFactory {
+        TransportFactory {
+            mode: mode,
+            settings: HttpSettings::default(),
+        }
+    }
+
+    /// True if responses come from recorded files instead of the network, in
+    /// which case nothing the providers receive should be persisted.
+    pub fn is_replay(&self) -> bool {
+        match self.mode {
+            TransportMode::Replay(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns a factory whose live transports use `settings`.
+    pub fn with_settings(&self, settings: HttpSettings) -> TransportFactory {
+        TransportFactory {
+            mode: self.mode.clone(),
+            settings: settings,
+        }
+    }
+
+    pub fn create(&self, name: &str) -> Box<HttpTransport> {
+        self.build(name, RedirectPolicy::FollowAll)
+    }
+
+    pub fn create_without_redirects(&self, name: &str) -> Box<HttpTransport> {
+        self.build(name, RedirectPolicy::FollowNone)
+    }
+
+    fn build(&self, name: &str, redirect_policy: RedirectPolicy) -> Box<HttpTransport> {
+        match self.mode {
+            TransportMode::Live => {
+                Box::new(HyperTransport::new(name, redirect_policy, &self.settings))
+            }
+            TransportMode::Record(ref dir) => {
+                Box::new(RecordingTransport::new(HyperTransport::new(name,
+                                                                     redirect_policy,
+                                                                     &self.settings),
+                                                 dir.join(name)))
+            }
+            TransportMode::Replay(ref dir) => Box::new(ReplayTransport::new(dir.join(name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper::client::RedirectPolicy;
+    use hyper::header::Headers;
+
+    use super::*;
+
+    /// Answers one request on each of `connections` connections, closing
+    /// each one afterwards even though the response allows keep-alive.
+    fn closing_server(connections: usize) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0; 1];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            }
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn retries_when_a_reused_connection_was_closed() {
+        let (url, handle) = closing_server(2);
+        let mut transport =
+            HyperTransport::new("test", RedirectPolicy::FollowAll, &HttpSettings::default());
+
+        let first = transport.send(HttpRequest::get(&url, Headers::new())).unwrap();
+        assert_eq!(first.body, "ok");
+        // let the close reach the idle connection in the pool
+        thread::sleep(Duration::from_millis(100));
+
+        let second = transport.send(HttpRequest::get(&url, Headers::new())).unwrap();
+        assert_eq!(second.body, "ok");
+        assert_eq!(transport.connections.load(Ordering::Relaxed), 2);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn does_not_retry_posts() {
+        let (url, handle) = closing_server(1);
+        let mut transport =
+            HyperTransport::new("test", RedirectPolicy::FollowAll, &HttpSettings::default());
+
+        transport.send(HttpRequest::get(&url, Headers::new())).unwrap();
+        handle.join().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // a retry would find nothing listening and fail to connect instead
+        match transport.send(HttpRequest::post(&url, Headers::new(), "a=b".to_owned())) {
+            Err(hyper::Error::Io(ref e)) => assert!(is_stale_connection(e), "{}", e),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected the closed connection to fail the request"),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{HttpRequest, HttpResponse, HttpTransport};
+use hyper;
+use serde_json;
+use private_file;
+
+/// One recorded request/response pair. Fixture directories hold one of these
+/// per file, replayed in file name order.
+#[derive(Serialize, Deserialize, Debug)]
+struct Exchange {
+    method: String,
+    url: String,
+    status: u16,
+    headers: BTreeMap<String, Vec<String>>,
+    body: String,
+}
+
+impl Exchange {
+    fn new(method: &Method, url: &str, response: &HttpResponse) -> Exchange {
+        // one entry per line, since joining them breaks headers like Set-Cookie
+        let mut headers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for header in response.headers.iter() {
+            let lines = response.headers.get_raw(header.name()).unwrap_or(&[]);
+            headers.insert(header.name().to_owned(),
+                           lines.iter().map(|x| String::from_utf8_lossy(x).into_owned()).collect());
+        }
+
+        Exchange {
+            method: method.to_string(),
+            url: url.to_owned(),
+            status: response.status.to_u16(),
+            headers: headers,
+            body: response.body.clone(),
+        }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let mut headers = Headers::new();
+        for (name, values) in self.headers {
+            headers.set_raw(name, values.into_iter().map(|x| x.into_bytes()).collect());
+        }
+
+        HttpResponse {
+            status: StatusCode::from_u16(self.status),
+            headers: headers,
+            body: self.body,
+        }
+    }
+}
+
+fn replay_error(msg: String) -> hyper::Error {
+    hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Serves responses previously captured by `RecordingTransport`. Requests must
+/// arrive in the same order, with the same method and URL, as when recorded.
+pub struct ReplayTransport {
+    dir: PathBuf,
+    exchanges: Option<VecDeque<Exchange>>,
+}
+
+impl ReplayTransport {
+    pub fn new(dir: PathBuf) -> ReplayTransport {
+        ReplayTransport {
+            dir: dir,
+            exchanges: None,
+        }
+    }
+
+    fn load(&self) -> hyper::Result<VecDeque<Exchange>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |x| x == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut exchanges = VecDeque::new();
+        for path in paths {
+            let file = File::open(&path)?;
+            match serde_json::from_reader(file) {
+                Ok(e) => exchanges.push_back(e),
+                Err(e) => return Err(replay_error(format!("{}: {}", path.display(), e))),
+            }
+        }
+
+        debug!("Loaded {} recorded responses from {}",
+               exchanges.len(),
+               self.dir.display());
+        Ok(exchanges)
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn send(&mut self, request: HttpRequest) -> hyper::Result<HttpResponse> {
+        if self.exchanges.is_none() {
+            self.exchanges = Some(self.load()?);
+        }
+
+        let exchange = match self.exchanges.as_mut().unwrap().pop_front() {
+            Some(e) => e,
+            None => {
+                return Err(replay_error(format!("No recorded response left for {} {}",
+                                                request.method,
+                                                request.url)))
+            }
+        };
+
+        if exchange.method != request.method.to_string() || exchange.url != request.url {
+            return Err(replay_error(format!("Expected request {} {}, got {} {}",
+                                            exchange.method,
+                                            exchange.url,
+                                            request.method,
+                                            request.url)));
+        }
+
+        Ok(exchange.into_response())
+    }
+}
+
+/// Passes requests through to another transport and writes every exchange to
+/// a directory that `ReplayTransport` can serve later. Recorded responses
+/// include whatever tokens the services hand out, so the files are only
+/// readable by the current user.
+///
+/// Numbering continues after the files already in the directory, so a
+/// restart or a config reload appends to the recording instead of
+/// overwriting its start.
+pub struct RecordingTransport<T: HttpTransport> {
+    inner: T,
+    dir: PathBuf,
+    count: Option<usize>,
+}
+
+impl<T: HttpTransport> RecordingTransport<T> {
+    pub fn new(inner: T, dir: PathBuf) -> RecordingTransport<T> {
+        RecordingTransport {
+            inner: inner,
+            dir: dir,
+            count: None,
+        }
+    }
+}
+
+/// The highest number among the recorded files in `dir`, 0 if there are none.
+fn last_recorded(dir: &Path) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut last = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().map_or(true, |x| x != "json") {
+            continue;
+        }
+        if let Some(n) = path.file_stem().and_then(|x| x.to_str()).and_then(|x| x.parse().ok()) {
+            last = cmp::max(last, n);
+        }
+    }
+
+    Ok(last)
+}
+
+impl<T: HttpTransport> HttpTransport for RecordingTransport<T> {
+    fn send(&mut self, request: HttpRequest) -> hyper::Result<HttpResponse> {
+        let method = request.method.clone();
+        let url = request.url.clone();
+        let response = self.inner.send(request)?;
+
+        let exchange = Exchange::new(&method, &url, &response);
+
+        let count = match self.count {
+            Some(n) => n + 1,
+            None => last_recorded(&self.dir)? + 1,
+        };
+        self.count = Some(count);
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{:04}.json", count));
+        let _ = fs::remove_file(&path);
+        let mut file = private_file::create(&path)?;
+        if let Err(e) = serde_json::to_writer_pretty(&mut file, &exchange) {
+            return Err(replay_error(format!("{}: {}", path.display(), e)));
+        }
+
+        debug!("Recorded {} {} to {}",
+               exchange.method,
+               exchange.url,
+               path.display());
+        Ok(response)
+    }
+}
+
+/// Helpers for tests that replay provider traffic.
+#[cfg(test)]
+pub mod testing {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use serde_json;
+    use super::Exchange;
+
+    /// An empty directory under the system temp directory.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let dir = env::temp_dir().join(format!("discord_console_status-{}-{}", name, nanos));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a fixture directory holding `(method, url, status, body)`
+    /// exchanges in order, as `RecordingTransport` would.
+    pub fn fixture_dir(name: &str, exchanges: &[(&str, &str, u16, &str)]) -> PathBuf {
+        const NO_HEADERS: &'static [(&'static str, &'static str)] = &[];
+        let exchanges: Vec<_> = exchanges.iter()
+            .map(|&(method, url, status, body)| (method, url, status, NO_HEADERS, body))
+            .collect();
+        fixture_dir_with_headers(name, &exchanges)
+    }
+
+    /// Like `fixture_dir`, with `(name, value)` response headers. A name that
+    /// appears more than once becomes a header with several lines.
+    pub fn fixture_dir_with_headers(name: &str,
+                                    exchanges: &[(&str, &str, u16, &[(&str, &str)], &str)])
+                                    -> PathBuf {
+        let dir = temp_dir(name);
+        for (i, &(method, url, status, header_lines, body)) in exchanges.iter().enumerate() {
+            let mut headers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for &(name, value) in header_lines {
+                headers.entry(name.to_owned()).or_insert(Vec::new()).push(value.to_owned());
+            }
+
+            let exchange = Exchange {
+                method: method.to_owned(),
+                url: url.to_owned(),
+                status: status,
+                headers: headers,
+                body: body.to_owned(),
+            };
+            let mut file = File::create(dir.join(format!("{:04}.json", i + 1))).unwrap();
+            serde_json::to_writer_pretty(&mut file, &exchange).unwrap();
+        }
+
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::fs;
+
+    use hyper;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    use super::*;
+    use super::testing::{fixture_dir, temp_dir};
+    use http::{HttpRequest, HttpResponse, HttpTransport};
+
+    struct StubTransport {
+        responses: VecDeque<HttpResponse>,
+    }
+
+    impl StubTransport {
+        fn new(responses: Vec<(Headers, &'static str)>) -> StubTransport {
+            StubTransport {
+                responses: responses.into_iter()
+                    .map(|(headers, body)| {
+                        HttpResponse {
+                            status: StatusCode::Ok,
+                            headers: headers,
+                            body: body.to_owned(),
+                        }
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    impl HttpTransport for StubTransport {
+        fn send(&mut self, _: HttpRequest) -> hyper::Result<HttpResponse> {
+            Ok(self.responses.pop_front().unwrap())
+        }
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let dir = fixture_dir("replay-order",
+                              &[("GET", "http://example.com/a", 200, "first"),
+                                ("POST", "http://example.com/b", 404, "second")]);
+        let mut transport = ReplayTransport::new(dir.clone());
+
+        let first = transport.send(HttpRequest::get("http://example.com/a", Headers::new()))
+            .unwrap();
+        assert_eq!(first.status, StatusCode::Ok);
+        assert_eq!(first.body, "first");
+
+        let second = transport.send(HttpRequest::post("http://example.com/b",
+                                                      Headers::new(),
+                                                      String::new()))
+            .unwrap();
+        assert_eq!(second.status, StatusCode::NotFound);
+        assert_eq!(second.body, "second");
+
+        assert!(transport.send(HttpRequest::get("http://example.com/a", Headers::new()))
+            .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unexpected_request() {
+        let dir = fixture_dir("replay-mismatch", &[("GET", "http://example.com/a", 200, "")]);
+        let mut transport = ReplayTransport::new(dir.clone());
+        assert!(transport.send(HttpRequest::get("http://example.com/b", Headers::new()))
+            .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recording_round_trips() {
+        let dir = temp_dir("record");
+        let stub = StubTransport::new(vec![(Headers::new(), "{\"token\":\"secret\"}")]);
+        let mut recorder = RecordingTransport::new(stub, dir.clone());
+        recorder.send(HttpRequest::get("http://example.com/token", Headers::new())).unwrap();
+
+        let path = dir.join("0001.json");
+        assert!(path.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let mut replay = ReplayTransport::new(dir.clone());
+        let response = replay.send(HttpRequest::get("http://example.com/token", Headers::new()))
+            .unwrap();
+        assert_eq!(response.body, "{\"token\":\"secret\"}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_header_lines_apart() {
+        let dir = temp_dir("record-headers");
+        let mut headers = Headers::new();
+        headers.set_raw("Set-Cookie",
+                        vec![b"npsso=abc; Expires=Wed, 21 Oct 2026 07:28:00 GMT".to_vec(),
+                             b"other=1; Path=/".to_vec()]);
+        let stub = StubTransport::new(vec![(headers, "")]);
+        let mut recorder = RecordingTransport::new(stub, dir.clone());
+        recorder.send(HttpRequest::get("http://example.com/", Headers::new())).unwrap();
+
+        let mut replay = ReplayTransport::new(dir.clone());
+        let response = replay.send(HttpRequest::get("http://example.com/", Headers::new()))
+            .unwrap();
+        assert_eq!(response.headers.get_raw("Set-Cookie").unwrap(),
+                   &[b"npsso=abc; Expires=Wed, 21 Oct 2026 07:28:00 GMT".to_vec(),
+                     b"other=1; Path=/".to_vec()][..]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recording_continues_after_existing_files() {
+        let dir = fixture_dir("record-append", &[("GET", "http://example.com/a", 200, "first")]);
+        let stub = StubTransport::new(vec![(Headers::new(), "second")]);
+        let mut recorder = RecordingTransport::new(stub, dir.clone());
+        recorder.send(HttpRequest::get("http://example.com/b", Headers::new())).unwrap();
+
+        let mut replay = ReplayTransport::new(dir.clone());
+        assert_eq!(replay.send(HttpRequest::get("http://example.com/a", Headers::new()))
+                       .unwrap()
+                       .body,
+                   "first");
+        assert_eq!(replay.send(HttpRequest::get("http://example.com/b", Headers::new()))
+                       .unwrap()
+                       .body,
+                   "second");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use hyper::Url;
+use hyper::net::OpensslClient;
+use openssl::ssl::{SslContext, SslMethod, SSL_OP_NO_COMPRESSION, SSL_OP_NO_SSLV2,
+                   SSL_OP_NO_SSLV3};
+
+use HJsonObject;
+use config::{ConfigError, Section};
+
+const HTTP_KEYS: &'static [&'static str] =
+    &["connect_timeout", "read_timeout", "write_timeout", "proxy", "ca_file", "user_agent"];
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Settings shared by every HTTP client the providers create, read from the
+/// optional top level `http` object. Timeouts are in seconds.
+#[derive(Clone, Debug)]
+pub struct HttpSettings {
+    /// Also bounds tunnelling through the proxy and the TLS handshake.
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    /// Host and port of an HTTP proxy, which is also used to tunnel HTTPS.
+    pub proxy: Option<(String, u16)>,
+    /// Replaces the User-Agent header the providers send.
+    pub user_agent: Option<String>,
+    pub ssl: OpensslClient,
+}
+
+impl Default for HttpSettings {
+    fn default() -> HttpSettings {
+        HttpSettings {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            read_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            write_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            proxy: None,
+            user_agent: None,
+            ssl: OpensslClient::default(),
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn from_config(config: &HJsonObject) -> Result<HttpSettings, ConfigError> {
+        let default = HttpSettings::default();
+        let http = match Section::root(config).object("http")? {
+            Some(s) => s,
+            None => return Ok(default),
+        };
+
+        http.check_keys(HTTP_KEYS)?;
+        let proxy = match http.string("proxy")? {
+            Some(url) => Some(parse_proxy(url).map_err(|e| http.invalid("proxy", &e))?),
+            None => None,
+        };
+
+        let ssl = match http.string("ca_file")? {
+            Some(path) => ssl_with_ca_file(path).map_err(|e| http.invalid("ca_file", &e))?,
+            None => default.ssl,
+        };
+
+        Ok(HttpSettings {
+            connect_timeout: http.duration_secs("connect_timeout", default.connect_timeout)?,
+            read_timeout: http.duration_secs("read_timeout", default.read_timeout)?,
+            write_timeout: http.duration_secs("write_timeout", default.write_timeout)?,
+            proxy: proxy,
+            user_agent: http.string("user_agent")?.map(|x| x.to_owned()),
+            ssl: ssl,
+        })
+    }
+}
+
+fn parse_proxy(url: &str) -> Result<(String, u16), String> {
+    let parsed = Url::parse(url).map_err(|e| e.to_string())?;
+    if parsed.scheme() != "http" {
+        return Err(format!("unsupported scheme '{}', expected http", parsed.scheme()));
+    }
+
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err("proxy credentials are not supported".to_owned());
+    }
+
+    match (parsed.host_str(), parsed.port_or_known_default()) {
+        (Some(host), Some(port)) => Ok((host.to_owned(), port)),
+        _ => Err("missing host".to_owned()),
+    }
+}
+
+/// Same setup as hyper's default client context, plus the certificates in
+/// `path`, which are trusted in addition to the system ones.
+fn ssl_with_ca_file(path: &str) -> Result<OpensslClient, String> {
+    let mut ctx = SslContext::new(SslMethod::Sslv23).map_err(|e| e.to_string())?;
+    ctx.set_default_verify_paths().map_err(|e| e.to_string())?;
+    ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION);
+    ctx.set_cipher_list("ALL!EXPORT!EXPORT40!EXPORT56!aNULL!LOW!RC4@STRENGTH")
+        .map_err(|e| e.to_string())?;
+    ctx.set_CA_file(path).map_err(|e| e.to_string())?;
+    Ok(OpensslClient::new(ctx))
+}
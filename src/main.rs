@@ -19,228 +19,556 @@ extern crate serde_hjson;
 extern crate log4rs;
 extern crate discord;
 extern crate rpassword;
-
-macro_rules! opt {
-    ($e:expr) => (match $e {
-        Some(val) => val,
-        None => return None,
-    });
-}
-
-macro_rules! json {
-    ($e:expr, $t:path) => (match *$e {
-        $t(ref val) => val,
-        _ => return None,
-    });
-}
+extern crate regex;
+extern crate openssl;
+extern crate websocket;
 
 mod xbl;
 mod psn;
 mod sigint;
 mod config;
+mod template;
+mod arbiter;
+mod http;
+mod backoff;
+mod session;
+mod gateway;
+mod sd_notify;
+mod daemon;
+mod shutdown;
+mod private_file;
 
 use std::io::{self, Write};
 use std::error;
+use std::mem;
+use std::process;
 use std::thread;
-use std::sync::mpsc::{Receiver, Sender, channel};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::HashMap;
-use std::time::Duration;
-use discord::model::Game;
+use std::time::{Duration, Instant};
 use clap::{Arg, App, SubCommand};
-use config::{PresenceMonitorConfig, TitleSetting};
-use std::any::TypeId;
+use config::{ConfigError, PresenceMonitorConfig, TitleSetting};
+use arbiter::PresenceArbiter;
+use template::TemplateValues;
+use http::{HttpSettings, TransportFactory, TransportMode};
+use backoff::{Backoff, BackoffPolicy};
+use session::DiscordSession;
+use sd_notify::Notifier;
+use daemon::PidFile;
+use shutdown::ShutdownToken;
+use std::path::PathBuf;
 use serde_hjson::Value as HJsonValue;
 use serde_hjson::Map as HJsonMap;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ActivityKind {
+    Playing,
+    Watching,
+}
+
+#[derive(Clone, Debug)]
 struct PresenceDetail {
     device: String,
     game: String,
+    /// The platform's own identifier for the title, which stays the same
+    /// across locales and name changes.
+    title_id: Option<String>,
     extended_info: Option<String>,
+    kind: ActivityKind,
 }
 
 type Presence = Option<PresenceDetail>;
 type HJsonObject = HJsonMap<String, HJsonValue>;
 
+/// Identifies one provider instance: the provider name ("xbl", "psn") and
+/// the label of the account it polls.
+#[derive(Clone, Debug)]
 struct PresenceProviderType {
-    id: TypeId,
     name: &'static str,
+    label: String,
+}
+
+impl PresenceProviderType {
+    /// "name:label", or just the name for providers without accounts.
+    fn key(&self) -> String {
+        if self.label.is_empty() {
+            self.name.to_owned()
+        } else {
+            format!("{}:{}", self.name, self.label)
+        }
+    }
 }
 
 trait PresenceProvider: std::marker::Send {
     fn get_presence(&mut self) -> Result<Presence, Box<error::Error>>;
     fn provider_type(&self) -> PresenceProviderType;
+
+    fn backoff_policy(&self) -> BackoffPolicy {
+        BackoffPolicy::default()
+    }
+
+    /// Lets a provider slow down polling below the configured update interval,
+    /// e.g. to stay within an API quota. Checked after every update.
+    fn min_update_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+struct ProviderThread {
+    name: &'static str,
+    stop: Arc<AtomicBool>,
+}
+
+/// A Discord user along with the presences that may be shown for it. The
+/// user's settings live in `PresenceMonitorConfig::discord_users` under the
+/// same label.
+struct DiscordUser {
+    label: String,
+    /// Only used to check the token, the session has its own connection.
+    discord: discord::Discord,
+    session: Option<DiscordSession>,
+    arbiter: PresenceArbiter,
+    last_status: Option<String>,
 }
 
 struct PresenceMonitor {
     config: PresenceMonitorConfig,
-    last_status: Option<String>,
-    discord: discord::Discord,
-    last_statuses: HashMap<TypeId, Option<String>>,
+    users: Vec<DiscordUser>,
+    transports: TransportFactory,
+    threads: HashMap<String, ProviderThread>,
+    update_interval: Arc<AtomicUsize>,
+    shutdown: ShutdownToken,
 }
 
+const RELOAD_CHECK_INTERVAL_SECS: u64 = 5;
+/// How often the main loop checks for a shutdown while no presence arrives.
+const SHUTDOWN_CHECK_INTERVAL_MS: u64 = 250;
+
 impl PresenceMonitor {
-    fn new(config: PresenceMonitorConfig) -> PresenceMonitor {
-        PresenceMonitor {
-            discord: discord::Discord::from_user_token(&config.discord_token).unwrap(),
-            config: config,
-            last_status: None,
-            last_statuses: HashMap::new(),
+    fn new(config: PresenceMonitorConfig,
+           transports: TransportFactory)
+           -> Result<PresenceMonitor, discord::Error> {
+        let mut users = Vec::new();
+        for user in &config.discord_users {
+            users.push(DiscordUser {
+                label: user.label.clone(),
+                discord: discord::Discord::from_user_token(&user.token)?,
+                session: None,
+                arbiter: PresenceArbiter::new(&config.provider_priority),
+                last_status: None,
+            });
         }
+
+        Ok(PresenceMonitor {
+            users: users,
+            update_interval: Arc::new(AtomicUsize::new(config.update_interval.as_secs() as usize)),
+            config: config,
+            transports: transports,
+            threads: HashMap::new(),
+            shutdown: ShutdownToken::new(),
+        })
     }
 
-    fn update_loop(update_interval: Duration,
+    fn update_loop(update_interval: Arc<AtomicUsize>,
                    mut provider: Box<PresenceProvider>,
                    sender: Sender<(PresenceProviderType, Presence)>,
-                   canceller: Arc<Condvar>,
-                   mutex: Arc<Mutex<u8>>) {
-        debug!("update_loop - {} - start", provider.provider_type().name);
+                   stop: Arc<AtomicBool>,
+                   shutdown: ShutdownToken) {
+        let name = provider.provider_type().key();
+        debug!("update_loop - {} - start", name);
 
+        let mut backoff = Backoff::new(provider.backoff_policy());
         loop {
             match provider.get_presence() {
                 Err(e) => {
-                    error!("update_loop - {} - {}", provider.provider_type().name, e);
+                    if backoff.record_failure() {
+                        error!("update_loop - {} - {} failures in a row, pausing provider: {}",
+                               name,
+                               backoff.failures(),
+                               e);
+                        // don't keep showing a presence that can no longer be checked
+                        if !stop.load(Ordering::Relaxed) {
+                            let _ = sender.send((provider.provider_type(), None));
+                        }
+                    } else if backoff.is_open() {
+                        debug!("update_loop - {} - probe failed: {}", name, e);
+                    } else {
+                        error!("update_loop - {} - {}", name, e);
+                    }
                 }
                 Ok(presence) => {
-                    let _ = sender.send((provider.provider_type(), presence));
+                    let failures = backoff.record_success();
+                    if failures > 0 {
+                        info!("update_loop - {} - recovered after {} failures", name, failures);
+                    }
+
+                    if !stop.load(Ordering::Relaxed) {
+                        let _ = sender.send((provider.provider_type(), presence));
+                    }
                 }
             }
 
-            debug!("update_loop - {} - awaiting lock", provider.provider_type().name);
-            let dummy_lock = (*mutex).lock().unwrap();
-            debug!("update_loop - {} - lock acquired", provider.provider_type().name);
-            if sigint::cancelled() {
-                debug!("update_loop - {} - exit", provider.provider_type().name);
-                return;
+            let mut interval = Duration::from_secs(update_interval.load(Ordering::Relaxed) as u64);
+            if let Some(min) = provider.min_update_interval() {
+                if min > interval {
+                    debug!("update_loop - {} - interval stretched to {}s", name, min.as_secs());
+                    interval = min;
+                }
+            }
+            let delay = backoff.next_delay(interval);
+            if delay != interval {
+                debug!("update_loop - {} - next update in {}s", name, delay.as_secs());
             }
+            let deadline = Instant::now() + delay;
 
-            debug!("update_loop - {} - awaiting condvar", provider.provider_type().name);
-            let _ = (*canceller).wait_timeout(dummy_lock, update_interval).unwrap();
-            debug!("update_loop - {} - condvar tripped or timed out", provider.provider_type().name);
-            if sigint::cancelled() {
-                debug!("update_loop - {} - exit", provider.provider_type().name);
-                return;
+            loop {
+                if shutdown.is_cancelled() || stop.load(Ordering::Relaxed) {
+                    debug!("update_loop - {} - exit", name);
+                    return;
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+
+                debug!("update_loop - {} - waiting", name);
+                shutdown.wait_timeout(deadline - now);
+                debug!("update_loop - {} - woken or timed out", name);
             }
         }
     }
 
-    fn spawn_threads(&mut self,
-                     canceller: Arc<Condvar>,
-                     mut providers: Vec<Box<PresenceProvider>>)
-                     -> Receiver<(PresenceProviderType, Presence)> {
-        let (sender, receiver) = channel::<(PresenceProviderType, Presence)>();
-        let mutex = Arc::new(Mutex::new(0u8));
-
-        for provider in providers.drain(0..) {
-            self.last_statuses.insert(provider.provider_type().id, None);
-            let update_interval = self.config.update_interval;
-            let sender_clone = sender.clone();
-            let canceller_clone = canceller.clone();
-            let mutex_clone = mutex.clone();
-            thread::spawn(move || {
-                PresenceMonitor::update_loop(update_interval,
-                                             provider,
-                                             sender_clone,
-                                             canceller_clone,
-                                             mutex_clone);
-            });
+    fn spawn_provider(&mut self,
+                      provider: Box<PresenceProvider>,
+                      sender: &Sender<(PresenceProviderType, Presence)>) {
+        let provider_type = provider.provider_type();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let update_interval = self.update_interval.clone();
+        let sender_clone = sender.clone();
+        let stop_clone = stop.clone();
+        let shutdown = self.shutdown.clone();
+        thread::spawn(move || {
+            PresenceMonitor::update_loop(update_interval,
+                                         provider,
+                                         sender_clone,
+                                         stop_clone,
+                                         shutdown);
+        });
+
+        self.threads.insert(provider_type.key(),
+                            ProviderThread {
+                                name: provider_type.name,
+                                stop: stop,
+                            });
+    }
+
+    /// Stops the provider instance with the given key ("name:label").
+    fn stop_provider(&mut self, key: &str) {
+        if let Some(thread) = self.threads.remove(key) {
+            thread.stop.store(true, Ordering::Relaxed);
         }
 
-        receiver
+        self.shutdown.wake();
+        for user in &mut self.users {
+            user.arbiter.retain(|_, x| x != key);
+        }
     }
 
-    fn make_status_string(&self, presence: &Presence) -> Option<String> {
-        match *presence {
-            None => None,
-            Some(ref detail) => {
-                let title_setting = match self.config.title_settings.get(&detail.game) {
-                    Some(s) => *s,
-                    None => TitleSetting::Full,
-                };
-
-                if title_setting == TitleSetting::Ignore {
-                    info!("Skipping '{}' due to 'ignore'", detail.game);
-                    return None;
-                }
+    /// Re-reads the config file if it changed on disk, or regardless when
+    /// `force` is set. Provider threads are only restarted when the section of
+    /// their own account changed; everything else is applied in place. A config that
+    /// fails to load is logged and ignored.
+    fn reload_if_changed(&mut self,
+                         sender: &Sender<(PresenceProviderType, Presence)>,
+                         force: bool)
+                         -> bool {
+        let modified = config::modified_time(&self.config.path);
+        if force {
+            info!("Reload requested, reloading config");
+        } else if modified.is_none() || modified == self.config.modified {
+            return false;
+        } else {
+            info!("Config file changed, reloading");
+        }
 
-                let mut new_status = format!("{}: {}", detail.device, detail.game);
-                if let Some(ref extended_info) = detail.extended_info {
-                    if title_setting == TitleSetting::NameOnly {
-                        info!("Skipping extended info for '{}' due to 'name-only'",
-                        detail.game);
-                    } else {
-                        new_status = format!("{} {}", new_status, extended_info);
-                    }
-                }
+        self.config.modified = modified;
+        let mut new_config = match PresenceMonitorConfig::from_file(&self.config.path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Config reload failed, keeping the current config: {}", e);
+                return false;
+            }
+        };
+
+        let providers = match PresenceMonitor::make_providers(&new_config, &self.transports) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Config reload failed, keeping the current config: {}", e);
+                return false;
+            }
+        };
+
+        for change in config::changed_keys(&self.config.json, &new_config.json) {
+            info!("Config reload - {}", change);
+        }
+
+        let same_users = {
+            let (old, new) = (&self.config.discord_users, &new_config.discord_users);
+            old.len() == new.len() &&
+            old.iter().zip(new.iter()).all(|(a, b)| a.label == b.label && a.token == b.token)
+        };
+        if !same_users {
+            warn!("Config reload - Discord user changes take effect after a restart");
+            new_config.discord_users = mem::replace(&mut self.config.discord_users, Vec::new());
+        }
+
+        if new_config.update_interval != self.config.update_interval {
+            info!("Config reload - update_interval {}s -> {}s",
+                  self.config.update_interval.as_secs(),
+                  new_config.update_interval.as_secs());
+            self.update_interval
+                .store(new_config.update_interval.as_secs() as usize, Ordering::Relaxed);
+        }
+
+        for user in &mut self.users {
+            user.arbiter.set_priority(&new_config.provider_priority);
+            if let Some(user_config) = new_config.discord_user(&user.label) {
+                user.arbiter.retain(|name, key| user_config.shows(name, key));
+            }
+        }
+
+        // every provider has a client built from the http settings. Restarting
+        // a PSN account rotates its refresh token, so the others are left alone.
+        let http_changed = self.config.json.get("http") != new_config.json.get("http");
+        let changed = {
+            let old = config::account_sections(&self.config.json);
+            let new = config::account_sections(&new_config.json);
+            self.threads
+                .keys()
+                .filter(|x| http_changed || old.get(*x) != new.get(*x))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for key in changed {
+            info!("Config reload - stopping {}", key);
+            self.stop_provider(&key);
+        }
+
+        for provider in providers {
+            let key = provider.provider_type().key();
+            if !self.threads.contains_key(&key) {
+                info!("Config reload - starting {}", key);
+                self.spawn_provider(provider, sender);
+            }
+        }
+
+        self.config = new_config;
+        true
+    }
+
+    /// Title settings of the Discord user are checked before the top level
+    /// ones.
+    fn make_status_string(&self, user: &str, detail: &PresenceDetail) -> Option<String> {
+        let normalized = if self.config.normalize_titles {
+            normalize_title(&detail.game)
+        } else {
+            detail.game.clone()
+        };
+
+        let title_id = detail.title_id.as_ref().map(|x| &x[..]);
+        let mut rule_sets = Vec::new();
+        if let Some(user_config) = self.config.discord_user(user) {
+            rule_sets.push(&user_config.title_rules);
+        }
+        rule_sets.push(&self.config.title_rules);
+
+        let title_config = rule_sets.iter()
+            .filter_map(|rules| rules.find(&detail.game, &normalized, title_id))
+            .next();
+        let title_setting = title_config.map_or(TitleSetting::Full, |x| x.setting);
+
+        if title_setting == TitleSetting::Ignore {
+            info!("Skipping '{}' due to 'ignore'", detail.game);
+            return None;
+        }
+
+        let mut extended_info = detail.extended_info.as_ref().map(|x| &x[..]);
+        if extended_info.is_some() && title_setting == TitleSetting::NameOnly {
+            info!("Skipping extended info for '{}' due to 'name-only'",
+                  detail.game);
+            extended_info = None;
+        }
+
+        let game = title_config.and_then(|x| x.display_name.as_ref()).unwrap_or(&normalized);
+        let default_format = match detail.kind {
+            ActivityKind::Playing => &self.config.status_format,
+            ActivityKind::Watching => &self.config.media_format,
+        };
+        let template = title_config.and_then(|x| x.format.as_ref()).unwrap_or(default_format);
+        template.render(&TemplateValues {
+            device: &detail.device,
+            game: game,
+            extended_info: extended_info,
+        })
+    }
+
+    fn update_status(&mut self, index: usize, trigger: &str) {
+        let (source, new_status) = {
+            let user = &self.users[index];
+            match user.arbiter.effective(|detail| self.make_status_string(&user.label, detail)) {
+                Some((key, status)) => (key.to_owned(), Some(status)),
+                None => (trigger.to_owned(), None),
+            }
+        };
 
-                Some(new_status)
+        let user = &mut self.users[index];
+        if new_status != user.last_status {
+            match new_status {
+                None => info!("{} - {} - clearing status", user.label, source),
+                Some(ref s) => info!("{} - {} - updating status to '{}'", user.label, source, s),
+            }
+
+            if let Some(ref session) = user.session {
+                session.set_status(new_status.clone());
+            }
+        } else if let Some(ref title) = new_status {
+            info!("{} - {} - status unchanged ('{}')", user.label, source, title);
+        } else {
+            info!("{} - {} - status unchanged (None)", user.label, source);
+        }
+
+        user.last_status = new_status;
+    }
+
+    /// Passes a presence to every Discord user that shows the provider.
+    fn dispatch(&mut self, provider_type: PresenceProviderType, presence: Presence) {
+        let key = provider_type.key();
+        debug!("{} - received presence {:?}", key, presence);
+
+        for i in 0..self.users.len() {
+            let shows = match self.config.discord_user(&self.users[i].label) {
+                Some(user_config) => user_config.shows(provider_type.name, &key),
+                None => true,
+            };
+
+            if shows {
+                self.users[i].arbiter.update(&provider_type, presence.clone());
+                self.update_status(i, &key);
             }
         }
     }
 
     fn run_loop(&mut self,
                 receiver: Receiver<(PresenceProviderType, Presence)>,
-                connection: &discord::Connection) {
-        for (provider_type, presence) in receiver.iter() {
-            let last_status = (*(self.last_statuses.get(&provider_type.id).unwrap())).clone();
-
-            let new_status = self.make_status_string(&presence);
-            if last_status != new_status || (self.last_status == None && new_status.is_some()) {
-                let game = match new_status {
-                    None => {
-                        info!("{} - clearing status", provider_type.name);
-                        None
-                    }
-                    Some(ref s) => {
-                        info!("{} - updating status to '{}'", provider_type.name, s);
-                        Some(Game::playing(s.clone()))
-                    }
-                };
+                sender: &Sender<(PresenceProviderType, Presence)>,
+                notifier: &mut Notifier) {
+        let reload_interval = Duration::from_secs(RELOAD_CHECK_INTERVAL_SECS);
+        let wait = Duration::from_millis(SHUTDOWN_CHECK_INTERVAL_MS);
+        let mut last_reload_check = Instant::now();
+
+        while !self.shutdown.is_cancelled() {
+            match receiver.recv_timeout(wait) {
+                Ok((provider_type, presence)) => self.dispatch(provider_type, presence),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            notifier.ping_watchdog();
 
-                connection.set_game(game);
-            } else if let Some(ref title) = new_status {
-                info!("{} - status unchanged ('{}')", provider_type.name, title);
+            let reloaded = if sigint::take_reload_request() {
+                notifier.reloading();
+                let reloaded = self.reload_if_changed(sender, true);
+                notifier.ready();
+                reloaded
+            } else if last_reload_check.elapsed() >= reload_interval {
+                last_reload_check = Instant::now();
+                self.reload_if_changed(sender, false)
             } else {
-                info!("{} - status unchanged (None)", provider_type.name);
-            }
+                false
+            };
 
-            self.last_statuses.insert(provider_type.id, new_status.clone());
-            self.last_status = new_status;
+            if reloaded {
+                for i in 0..self.users.len() {
+                    self.update_status(i, "config");
+                }
+            }
         }
     }
 
-    fn make_providers(&self) -> Vec<Box<PresenceProvider>> {
+    fn make_providers(config: &PresenceMonitorConfig,
+                      transports: &TransportFactory)
+                      -> Result<Vec<Box<PresenceProvider>>, ConfigError> {
+        let transports = &transports.with_settings(HttpSettings::from_config(&config.json)?);
         let mut providers: Vec<Box<PresenceProvider>> = Vec::new();
-        if let Some(s) = xbl::XblPresenceProvider::from_config(&self.config.json) {
+        for s in xbl::XblPresenceProvider::from_config(&config.json, transports)? {
             providers.push(Box::new(s));
         }
 
-        if let Some(s) = psn::PsnPresenceProvider::from_config(&self.config.json) {
+        for s in psn::PsnPresenceProvider::from_config(&config.json, transports)? {
             providers.push(Box::new(s));
         }
 
-        if let Some(s) = DummyProvider::from_config(&self.config.json) {
+        if let Some(s) = DummyProvider::from_config(&config.json) {
             providers.push(Box::new(s));
         }
 
-        providers
+        let keys = providers.iter().map(|x| x.provider_type().key()).collect::<Vec<_>>();
+        config.check_account_refs(&keys)?;
+        Ok(providers)
     }
 
-    fn run(&mut self) {
-        let (connection, ready_event) = self.discord.connect().unwrap();
-        info!("Discord logged in as {}", ready_event.user.username);
-        let canceller = Arc::new(Condvar::new());
+    fn run(&mut self, providers: Vec<Box<PresenceProvider>>) {
+        for user in &mut self.users {
+            if let Some(user_config) = self.config.discord_user(&user.label) {
+                user.session = Some(DiscordSession::start(&user.label, &user_config.token));
+            }
+        }
 
-        sigint::set_ctrlc_handler(&*canceller);
+        sigint::set_ctrlc_handler(&self.shutdown);
+
+        let (sender, receiver) = channel::<(PresenceProviderType, Presence)>();
+        for provider in providers {
+            self.spawn_provider(provider, &sender);
+        }
 
-        let providers = self.make_providers();
-        let receiver = self.spawn_threads(canceller.clone(), providers);
+        let mut notifier = Notifier::from_env();
+        notifier.ready();
+        self.run_loop(receiver, &sender, &mut notifier);
 
-        self.run_loop(receiver, &connection);
+        notifier.stopping();
+        self.clean_up();
+    }
 
+    /// Stops the provider threads and waits for every Discord session to clear
+    /// its status, giving up after the configured shutdown timeout. Provider
+    /// threads stuck in a request are not waited for.
+    fn clean_up(&mut self) {
         info!("Cleaning up and resetting status");
-        connection.set_game(None);
+        self.shutdown.cancel();
+        for thread in self.threads.values() {
+            thread.stop.store(true, Ordering::Relaxed);
+        }
+
+        let sessions = self.users
+            .iter()
+            .filter_map(|x| x.session.as_ref().map(|s| (&x.label, s)))
+            .collect::<Vec<_>>();
+        for &(_, session) in &sessions {
+            session.stop();
+        }
+
+        let deadline = Instant::now() + self.config.shutdown_timeout;
+        for &(label, session) in &sessions {
+            if !session.wait(deadline) {
+                warn!("{} - Discord status not cleared within {}s, exiting anyway",
+                      label,
+                      self.config.shutdown_timeout.as_secs());
+            }
+        }
     }
 }
 
@@ -253,8 +581,8 @@ impl PresenceProvider for DummyProvider {
 
     fn provider_type(&self) -> PresenceProviderType {
         PresenceProviderType {
-            id: TypeId::of::<Self>(),
             name: "dummy",
+            label: String::new(),
         }
     }
 }
@@ -268,14 +596,98 @@ impl DummyProvider {
     }
 }
 
-fn try_main(config_path: &str) -> Result<(), Box<error::Error>> {
-    let config = PresenceMonitorConfig::from_file(config_path)?;
-    let mut monitor = PresenceMonitor::new(config);
-    monitor.run();
+/// Strips trademark symbols and collapses runs of whitespace, so
+/// "Call of Duty®: Black Ops  III" becomes "Call of Duty: Black Ops III".
+fn normalize_title(title: &str) -> String {
+    title.chars()
+        .filter(|c| !['®', '™', '℠'].contains(c))
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn try_main(config: PresenceMonitorConfig,
+            transports: TransportFactory)
+            -> Result<(), Box<error::Error>> {
+    let mut monitor = PresenceMonitor::new(config, transports)?;
+    let providers = PresenceMonitor::make_providers(&monitor.config, &monitor.transports)?;
+    monitor.run(providers);
     Ok(())
 }
 
-fn get_psn_token() -> Result<(), Box<error::Error>> {
+fn report_check(component: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("{:<10} ok      {}", component, detail);
+            true
+        }
+        Err(e) => {
+            println!("{:<10} FAILED  {}", component, e);
+            false
+        }
+    }
+}
+
+/// Loads the config, polls every provider once and checks the Discord token,
+/// printing one line per component. Returns whether everything passed.
+fn check_config(config_path: &str, transports: TransportFactory) -> bool {
+    let config = match PresenceMonitorConfig::from_file(config_path) {
+        Ok(c) => c,
+        Err(e) => return report_check("config", Err(e.to_string())),
+    };
+    report_check("config", Ok(config_path.to_owned()));
+
+    let monitor = match PresenceMonitor::new(config, transports) {
+        Ok(m) => m,
+        Err(e) => return report_check("discord", Err(e.to_string())),
+    };
+
+    let providers = match PresenceMonitor::make_providers(&monitor.config, &monitor.transports) {
+        Ok(p) => p,
+        Err(e) => return report_check("providers", Err(e.to_string())),
+    };
+
+    let mut passed = true;
+    if providers.is_empty() {
+        passed &= report_check("providers", Err("no providers configured".to_owned()));
+    }
+
+    for mut provider in providers {
+        let result = match provider.get_presence() {
+            Ok(Some(detail)) => Ok(format!("{}: {}", detail.device, detail.game)),
+            Ok(None) => Ok("no game running".to_owned()),
+            Err(e) => Err(e.to_string()),
+        };
+        passed &= report_check(&provider.provider_type().key(), result);
+    }
+
+    for user in &monitor.users {
+        let result = match user.discord.get_servers() {
+            Ok(servers) => Ok(format!("token accepted ({} servers)", servers.len())),
+            Err(e) => Err(e.to_string()),
+        };
+        passed &= report_check(&format!("discord:{}", user.label), result);
+    }
+
+    passed
+}
+
+fn get_psn_token(config_path: &str,
+                 transports: TransportFactory)
+                 -> Result<(), Box<error::Error>> {
+    let (endpoints, transports) = match PresenceMonitorConfig::from_file(config_path) {
+        Ok(config) => {
+            (psn::PsnEndpoints::from_config(&config.json)?,
+             transports.with_settings(HttpSettings::from_config(&config.json)?))
+        }
+        Err(e) => {
+            warn!("Using default PSN endpoints and HTTP settings, config not loaded: {}",
+                  e);
+            (psn::PsnEndpoints::default(), transports)
+        }
+    };
+
     let mut stdout = io::stdout();
     write!(stdout, "Username: ").unwrap();
     stdout.flush().unwrap();
@@ -287,8 +699,10 @@ fn get_psn_token() -> Result<(), Box<error::Error>> {
 
     let password = rpassword::prompt_password_stdout("Password: ").unwrap();
 
-    let (_, refresh_token) = psn::PsnPresenceProvider::perform_login(&username, &password)?;
-    println!("{}", refresh_token);
+    let mut transport = transports.create_without_redirects("psn-login");
+    let tokens =
+        psn::PsnPresenceProvider::perform_login(&username, &password, &endpoints, &mut *transport)?;
+    println!("{}", tokens.refresh_token);
     Ok(())
 }
 
@@ -301,31 +715,128 @@ fn main() {
             .short("c")
             .long("config")
             .value_name("FILE")
-            .help("Overrides the default config file path")
-            .default_value("config.hjson")
+            .help("Overrides the default config file path (config.hjson in the working \
+                   directory, or else in the XDG config directory)")
             .takes_value(true))
         .arg(Arg::with_name("log-config")
             .short("l")
             .long("log-config")
             .value_name("FILE")
-            .help("Overrides the default log4rs file path")
-            .default_value("log4rs.yaml")
+            .help("Overrides the default log4rs file path (found like the config file)")
+            .takes_value(true))
+        .arg(Arg::with_name("foreground")
+            .short("f")
+            .long("foreground")
+            .help("Stays attached to the terminal instead of running in the background"))
+        .arg(Arg::with_name("pidfile")
+            .long("pidfile")
+            .value_name("FILE")
+            .help("Writes the process ID to FILE while running")
+            .takes_value(true))
+        .arg(Arg::with_name("record-http")
+            .long("record-http")
+            .value_name("DIR")
+            .help("Writes every HTTP exchange with the console services to DIR as fixtures")
+            .conflicts_with("replay-http")
+            .takes_value(true))
+        .arg(Arg::with_name("replay-http")
+            .long("replay-http")
+            .value_name("DIR")
+            .help("Serves console service responses from fixtures in DIR instead of the network")
             .takes_value(true))
         .subcommand(SubCommand::with_name("get-psn-token")
             .about("Retrieves a refresh token to enter into the configuration file for \
                     connecting to Playstation Network"))
+        .subcommand(SubCommand::with_name("check-config")
+            .about("Validates the configuration file, polls each provider once and checks the \
+                    Discord token"))
         .get_matches();
 
-    log4rs::init_file(matches.value_of("log-config").unwrap(), Default::default()).unwrap();
+    let config = matches.value_of("config")
+        .map(|x| x.to_owned())
+        .unwrap_or_else(|| daemon::default_path("config.hjson"));
+    let log_config = matches.value_of("log-config")
+        .map(|x| x.to_owned())
+        .unwrap_or_else(|| daemon::default_path("log4rs.yaml"));
+
+    if let Err(e) = log4rs::init_file(&log_config, Default::default()) {
+        writeln!(io::stderr(), "Failed to set up logging from {}: {}", log_config, e).unwrap();
+        process::exit(1);
+    }
+
+    // load the config while errors can still reach the terminal and the exit
+    // status
+    let run_monitor = matches.subcommand_name().is_none();
+    let monitor_config = if run_monitor {
+        match PresenceMonitorConfig::from_file(&config) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // systemd with Type=notify expects the process it started to stay around.
+    // Logging is already set up, so log4rs won't reload its config file after
+    // this: the thread watching it doesn't survive the fork.
+    if run_monitor && cfg!(unix) && !matches.is_present("foreground") &&
+       !Notifier::from_env().is_enabled() {
+        if let Err(e) = daemon::daemonize() {
+            error!("Failed to run in the background: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let _pid_file = match matches.value_of("pidfile") {
+        Some(path) if run_monitor => {
+            match PidFile::create(path) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    error!("Failed to write pid file {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
 
-    let result = if let Some(_) = matches.subcommand_matches("get-psn-token") {
-        get_psn_token()
+    let transport_mode = if let Some(dir) = matches.value_of("record-http") {
+        TransportMode::Record(PathBuf::from(dir))
+    } else if let Some(dir) = matches.value_of("replay-http") {
+        TransportMode::Replay(PathBuf::from(dir))
     } else {
-        let config = matches.value_of("config").unwrap();
-        try_main(&config)
+        TransportMode::Live
+    };
+    let transports = TransportFactory::new(transport_mode);
+
+    if let Some(_) = matches.subcommand_matches("check-config") {
+        let passed = check_config(&config, transports);
+        process::exit(if passed { 0 } else { 1 });
+    }
+
+    let result = match monitor_config {
+        Some(c) => try_main(c, transports),
+        None => get_psn_token(&config, transports),
     };
 
     if let Err(e) = result {
         error!("{}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_title;
+
+    #[test]
+    fn normalize_title_strips_marks_and_spaces() {
+        assert_eq!(normalize_title("Call of Duty®: Black Ops III"),
+                   "Call of Duty: Black Ops III");
+        assert_eq!(normalize_title("FIFA™  17 ℠"), "FIFA 17");
+        assert_eq!(normalize_title("  Halo 5\tGuardians "), "Halo 5 Guardians");
+        assert_eq!(normalize_title("Bloodborne"), "Bloodborne");
+    }
+}
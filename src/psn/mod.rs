@@ -1,24 +1,30 @@
-extern crate regex;
 extern crate select;
 
 mod responses;
+mod token_store;
 
-use hyper::client::Client as HttpClient;
 use hyper::header::{Headers, UserAgent, Origin, ContentType, ContentLength, Authorization,
                     CacheControl, Bearer, Cookie, CookiePair, Location, Host, SetCookie,
                     CacheDirective};
 use hyper::mime::{Mime, TopLevel, SubLevel};
-use std::io::Read;
+use hyper::status::StatusCode;
 use std::iter::Iterator;
-use std::any::TypeId;
-use serde_hjson::Value as HJsonValue;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use HJsonObject;
 use PresenceProvider;
 use Presence;
 use PresenceDetail;
+use ActivityKind;
 use PresenceProviderType;
+use config::{self, ConfigError, Section};
+use http::{HttpRequest, HttpTransport, TransportFactory};
+use backoff::BackoffPolicy;
+use daemon;
 use serde_json;
+use regex;
+use self::token_store::TokenStore;
 
 use std::io;
 use std::error;
@@ -27,7 +33,15 @@ use hyper;
 
 header! { (XRequestedWith, "X-Requested-With") => [String] }
 
-const BASE_URL: &'static str = "https://auth.api.sonyentertainmentnetwork.com";
+lazy_static! {
+    static ref REFRESH_LOCK: Mutex<()> = Mutex::new(());
+}
+
+const DEFAULT_AUTH_URL: &'static str = "https://auth.api.sonyentertainmentnetwork.com";
+const DEFAULT_PROFILE_URL: &'static str = "https://us-prof.np.community.playstation.net";
+const DEFAULT_TOKEN_STORE: &'static str = "psn_tokens.json";
+/// How long before the access token expires it gets refreshed.
+const REFRESH_MARGIN_SECS: u64 = 60;
 const SERVICE_ENTITY: &'static str = "urn:service-entity:psn";
 const STATE: &'static str = "x";
 const REDIRECT_URL: &'static str = "com.scee.psxandroid.scecompcall://redirect";
@@ -59,10 +73,55 @@ lazy_static! {
     static ref CODE_REGEX: regex::Regex = regex::Regex::new(r"code=(.{6})").unwrap();
 }
 
+/// Hosts the provider talks to, overridable through `psn.endpoints`.
+#[derive(Clone)]
+pub struct PsnEndpoints {
+    auth: String,
+    profile: String,
+}
+
+impl Default for PsnEndpoints {
+    fn default() -> PsnEndpoints {
+        PsnEndpoints {
+            auth: DEFAULT_AUTH_URL.to_owned(),
+            profile: DEFAULT_PROFILE_URL.to_owned(),
+        }
+    }
+}
+
+impl PsnEndpoints {
+    /// Uses the endpoints of the first account if `psn` is an array.
+    pub fn from_config(config: &HJsonObject) -> Result<PsnEndpoints, ConfigError> {
+        match Section::root(config).objects("psn")?.first() {
+            Some(section) => PsnEndpoints::from_section(section),
+            None => Ok(PsnEndpoints::default()),
+        }
+    }
+
+    fn from_section(section: &Section) -> Result<PsnEndpoints, ConfigError> {
+        let endpoints = config::read_endpoints(section,
+                                               &[("auth", DEFAULT_AUTH_URL),
+                                                 ("profile", DEFAULT_PROFILE_URL)])?;
+        Ok(PsnEndpoints {
+            auth: endpoints["auth"].clone(),
+            profile: endpoints["profile"].clone(),
+        })
+    }
+}
+
 pub struct PsnPresenceProvider {
+    label: String,
     psn_id: String,
+    config_token: String,
     refresh_token: String,
     access_token: String,
+    access_expires: Option<Instant>,
+    endpoints: PsnEndpoints,
+    /// `None` when replaying recorded traffic, so replayed tokens never
+    /// replace live ones.
+    token_store: Option<TokenStore>,
+    backoff: BackoffPolicy,
+    transport: Box<HttpTransport>,
 }
 
 quick_error! {
@@ -98,9 +157,24 @@ quick_error! {
             description("missing response field")
             display("Response missing field: {}", name)
         }
+        Unauthorized(status: StatusCode) {
+            description("access token rejected")
+            display("PSN rejected the access token: {}", status)
+        }
+        Status(status: StatusCode) {
+            description("unexpected http status")
+            display("Unexpected HTTP status: {}", status)
+        }
     }
 }
 
+/// Tokens handed out by the PSN OAuth endpoint.
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: Option<Duration>,
+}
+
 
 fn urlencode(string: &str) -> String {
     let mut result = String::new();
@@ -147,31 +221,150 @@ impl PsnPresenceProvider {
         headers
     }
 
-    pub fn new(psn_id: &str, refresh_token: &str) -> PsnPresenceProvider {
+    /// `refresh_token` is the token from the config file. If the token store
+    /// has a newer token that was rotated from it, that one is used instead.
+    /// The store is only read when refreshing, so a provider built while an
+    /// old one for the same account is still running never starts out with a
+    /// token the old one is about to rotate.
+    pub fn new(label: &str,
+               psn_id: &str,
+               refresh_token: &str,
+               endpoints: PsnEndpoints,
+               token_store: Option<TokenStore>,
+               backoff: BackoffPolicy,
+               transport: Box<HttpTransport>)
+               -> PsnPresenceProvider {
         PsnPresenceProvider {
+            label: label.to_owned(),
             psn_id: psn_id.to_owned(),
+            config_token: refresh_token.to_owned(),
             refresh_token: refresh_token.to_owned(),
             access_token: "".to_owned(),
+            access_expires: None,
+            endpoints: endpoints,
+            token_store: token_store,
+            backoff: backoff,
+            transport: transport,
+        }
+    }
+
+    /// `psn` is either one account object or an array of them.
+    pub fn from_config(config: &HJsonObject,
+                       transports: &TransportFactory)
+                       -> Result<Vec<PsnPresenceProvider>, ConfigError> {
+        let mut providers = Vec::new();
+        let mut labels = Vec::new();
+        for section in Section::root(config).objects("psn")? {
+            section.check_keys(&["id",
+                                 "label",
+                                 "refresh_token",
+                                 "token_store",
+                                 "endpoints",
+                                 "backoff"])?;
+            let id = section.required_string("id")?;
+            let label = config::read_label(&section, id, &mut labels)?;
+            let refresh_token = section.required_string("refresh_token")?;
+            let token_store_path = section.string("token_store")?
+                .map(|x| x.to_owned())
+                .unwrap_or_else(|| daemon::default_state_path(DEFAULT_TOKEN_STORE));
+            let token_store = if transports.is_replay() {
+                None
+            } else {
+                Some(TokenStore::new(token_store_path))
+            };
+            let endpoints = PsnEndpoints::from_section(&section)?;
+            let backoff = BackoffPolicy::from_section(&section)?;
+            let transport = transports.create(&format!("psn-{}", label));
+
+            providers.push(PsnPresenceProvider::new(&label,
+                                                    id,
+                                                    refresh_token,
+                                                    endpoints,
+                                                    token_store,
+                                                    backoff,
+                                                    transport));
+        }
+
+        Ok(providers)
+    }
+
+    /// True if there is no access token yet or it expires within
+    /// `REFRESH_MARGIN_SECS`. Without an `expires_in` from PSN the token is
+    /// only refreshed once a request is rejected.
+    fn access_token_expiring(&self) -> bool {
+        if self.access_token.is_empty() {
+            return true;
+        }
+
+        match self.access_expires {
+            Some(expires) => Instant::now() + Duration::from_secs(REFRESH_MARGIN_SECS) >= expires,
+            None => false,
         }
     }
 
-    pub fn from_config(config: &HJsonObject) -> Option<PsnPresenceProvider> {
-        let psn_obj = json!(opt!(config.get("psn")), HJsonValue::Object);
-        let id = json!(opt!(psn_obj.get("id")), HJsonValue::String);
-        let refresh_token = json!(opt!(psn_obj.get("refresh_token")), HJsonValue::String);
+    /// Picks up a token another provider for the same account rotated since
+    /// this one last refreshed. Must be called with `REFRESH_LOCK` held.
+    fn load_stored_token(&mut self) {
+        let stored = match self.token_store {
+            Some(ref store) => store.load(&self.psn_id, &self.config_token),
+            None => return,
+        };
 
-        Some(PsnPresenceProvider::new(&id, &refresh_token))
+        match stored {
+            Ok(Some(ref t)) if *t == self.refresh_token => {}
+            Ok(Some(t)) => {
+                info!("Using stored PSN refresh token for {}", self.psn_id);
+                self.refresh_token = t;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Could not read PSN token store, using the current refresh token: {}",
+                      e);
+            }
+        }
     }
 
     pub fn refresh(&mut self) -> Result<(), PsnError> {
+        // held across the request so that a provider being replaced on reload
+        // and its replacement never both rotate the same refresh token
+        let _lock = REFRESH_LOCK.lock().unwrap();
+        self.load_stored_token();
+
         self.access_token = "".to_owned();
-        let mut client = HttpClient::new();
-        let tokens = PsnPresenceProvider::refresh_access_token(&mut client, &self.refresh_token)?;
-        self.access_token = tokens.0;
-        self.refresh_token = tokens.1;
+        self.access_expires = None;
+        let tokens = PsnPresenceProvider::refresh_access_token(&mut *self.transport,
+                                                               &self.endpoints,
+                                                               &self.refresh_token)?;
+        self.access_token = tokens.access_token;
+        self.refresh_token = tokens.refresh_token;
+        self.access_expires = tokens.expires_in.map(|x| Instant::now() + x);
+
+        // PSN has already invalidated the old token, so failing to save the new
+        // one only matters after a restart
+        if let Some(ref store) = self.token_store {
+            if let Err(e) = store.save(&self.psn_id, &self.config_token, &self.refresh_token) {
+                error!("Could not save the new PSN refresh token: {}", e);
+            }
+        }
         Ok(())
     }
 
+    fn profile_url(&self) -> String {
+        // there's a good chance the request will work without most of these
+        // should check at some point
+        let data = make_url_query(&[("fields", "presences(@titleInfo,gameStatus)"),
+                                    ("avatarSizes", "m"),
+                                    ("profilePictureSizes", "m"),
+                                    ("languagesUsedLanguageSet", "set3"),
+                                    ("psVitaTitleIcon", "circled"),
+                                    ("titleIconSize", "s")]);
+
+        format!("{}/userProfile/v1/users/{}/profile2?{}",
+                self.endpoints.profile,
+                self.psn_id,
+                data)
+    }
+
     fn get_profile(&mut self) -> Result<responses::Profile, PsnError> {
         info!("Requesting presence data from PSN");
         let mut headers = Headers::new();
@@ -187,57 +380,38 @@ impl PsnPresenceProvider {
         headers.set(Authorization(Bearer { token: self.access_token.clone() }));
         headers.set(CacheControl(vec![CacheDirective::NoCache]));
 
-        // there's a good chance the request will work without most of these
-        // should check at some point
-        let data = make_url_query(&[("fields", "presences(@titleInfo)"),
-                                    ("avatarSizes", "m"),
-                                    ("profilePictureSizes", "m"),
-                                    ("languagesUsedLanguageSet", "set3"),
-                                    ("psVitaTitleIcon", "circled"),
-                                    ("titleIconSize", "s")]);
-
-        let url = format!("https://us-prof.np.community.playstation.\
-                           net/userProfile/v1/users/{}/profile2?{}",
-                          self.psn_id,
-                          data);
+        let url = self.profile_url();
+        let resp = self.transport.send(HttpRequest::get(&url, headers))?;
 
-        let client = HttpClient::new();
-        let req = client.get(&url)
-            .headers(headers);
+        debug!("{}", resp.body);
 
-        let mut resp = req.send()?;
-        let mut json = String::new();
-        resp.read_to_string(&mut json)?;
-
-        debug!("{}", json);
+        match resp.status {
+            StatusCode::Unauthorized => return Err(PsnError::Unauthorized(resp.status)),
+            s if !s.is_success() => return Err(PsnError::Status(s)),
+            _ => {}
+        }
 
-        let profile_wrapper = serde_json::from_str::<responses::ProfileWrapper>(&json)?;
+        let profile_wrapper = serde_json::from_str::<responses::ProfileWrapper>(&resp.body)?;
         Ok(profile_wrapper.profile)
     }
 
-    fn request_ssocookie(client: &mut HttpClient,
+    fn request_ssocookie(transport: &mut HttpTransport,
+                         endpoints: &PsnEndpoints,
                          username: &str,
                          password: &str)
                          -> Result<Vec<CookiePair>, PsnError> {
         info!("Requesting SSO cookie from PSN");
-        let url = format!("{}/2.0/ssocookie", BASE_URL);
+        let url = format!("{}/2.0/ssocookie", endpoints.auth);
         let data = make_url_query(&[("authentication_type", "password"),
                                     ("username", username),
                                     ("password", password),
                                     ("client_id", CLIENT_ID1)]);
         let headers = PsnPresenceProvider::default_post_headers(&data);
 
-        let req = client.post(&url)
-            .headers(headers)
-            .body(&data);
-
-        let mut resp = req.send()?;
+        let resp = transport.send(HttpRequest::post(&url, headers, data))?;
+        debug!("{}", resp.body);
 
-        let mut resp_body = String::new();
-        resp.read_to_string(&mut resp_body)?;
-        debug!("{}", resp_body);
-
-        if let Ok(err) = serde_json::from_str::<responses::GenericError>(&resp_body) {
+        if let Ok(err) = serde_json::from_str::<responses::GenericError>(&resp.body) {
             if let Some(error_code) = err.error_code {
                 return Err(PsnError::Api(error_code,
                                          err.error_description
@@ -251,11 +425,12 @@ impl PsnPresenceProvider {
         }
     }
 
-    fn exchange_ssocookie_for_access_token(client: &mut HttpClient,
+    fn exchange_ssocookie_for_access_token(transport: &mut HttpTransport,
+                                           endpoints: &PsnEndpoints,
                                            cookies: &Vec<CookiePair>)
                                            -> Result<String, PsnError> {
         info!("Requesting access token from PSN");
-        let url = format!("{}/2.0/oauth/token", BASE_URL);
+        let url = format!("{}/2.0/oauth/token", endpoints.auth);
         let data = make_url_query(&[("grant_type", "sso_cookie"),
                                     ("scope", SCOPES1),
                                     ("client_id", CLIENT_ID1),
@@ -263,23 +438,14 @@ impl PsnPresenceProvider {
         let mut headers = PsnPresenceProvider::default_post_headers(&data);
         headers.set(Cookie(cookies.clone()));
 
-        let req = client.post(&url)
-            .headers(headers)
-            .body(&data);
-
-        let mut resp = req.send()?;
-        let mut resp_body = String::new();
-        resp.read_to_string(&mut resp_body)?;
-        debug!("{}", resp_body);
-        let authorization: responses::Authorization = serde_json::from_str(&resp_body)?;
+        let resp = transport.send(HttpRequest::post(&url, headers, data))?;
+        debug!("{}", resp.body);
+        let authorization: responses::Authorization = serde_json::from_str(&resp.body)?;
         authorization.access_token.ok_or(PsnError::MissingField("access_token"))
     }
 
-    fn request_login_code(client: &mut HttpClient,
-                          cookies: &Vec<CookiePair>)
-                          -> Result<String, PsnError> {
-        info!("Requesting login code from PSN");
-        let url = format!("{}/2.0/oauth/authorize", BASE_URL);
+    fn login_code_url(endpoints: &PsnEndpoints) -> String {
+        let url = format!("{}/2.0/oauth/authorize", endpoints.auth);
         let data = make_url_query(&[("state", STATE),
                                     ("duid", DUID),
                                     ("ui", "pr"),
@@ -295,14 +461,19 @@ impl PsnPresenceProvider {
                                     ("smcid", "psapp:signin"),
                                     ("support_scheme", "sneiprls"),
                                     ("tp_psn", "true")]);
+        format!("{}?{}", url, data)
+    }
+
+    fn request_login_code(transport: &mut HttpTransport,
+                          endpoints: &PsnEndpoints,
+                          cookies: &Vec<CookiePair>)
+                          -> Result<String, PsnError> {
+        info!("Requesting login code from PSN");
         let mut headers = PsnPresenceProvider::default_headers();
         headers.set(Cookie(cookies.clone()));
 
-        let url = format!("{}?{}", url, data);
-        let req = client.get(&url)
-            .headers(headers);
-
-        let resp = req.send()?;
+        let url = PsnPresenceProvider::login_code_url(endpoints);
+        let resp = transport.send(HttpRequest::get(&url, headers))?;
         match resp.headers.get::<Location>() {
             Some(l) => {
                 match CODE_REGEX.captures(&l) {
@@ -314,12 +485,13 @@ impl PsnPresenceProvider {
         }
     }
 
-    pub fn request_full_token(client: &mut HttpClient,
+    pub fn request_full_token(transport: &mut HttpTransport,
+                              endpoints: &PsnEndpoints,
                               cookies: &Vec<CookiePair>,
                               login_code: &str)
-                              -> Result<(String, String), PsnError> {
+                              -> Result<Tokens, PsnError> {
         info!("Requesting full token from PSN");
-        let url = format!("{}/2.0/oauth/token", BASE_URL);
+        let url = format!("{}/2.0/oauth/token", endpoints.auth);
         let data = make_url_query(&[("grant_type", "authorization_code"),
                                     ("client_id", CLIENT_ID2),
                                     ("client_secret", CLIENT_SECRET2),
@@ -336,23 +508,18 @@ impl PsnPresenceProvider {
         headers.set(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![])));
         headers.set(ContentLength(data.len() as u64));
 
-        let req = client.post(&url)
-            .headers(headers)
-            .body(&data);
+        let resp = transport.send(HttpRequest::post(&url, headers, data))?;
+        debug!("{}", resp.body);
 
-        let mut resp = req.send()?;
-        let mut resp_body = String::new();
-        resp.read_to_string(&mut resp_body)?;
-        debug!("{}", resp_body);
-
-        PsnPresenceProvider::unpack_authorization(&resp_body)
+        PsnPresenceProvider::unpack_authorization(&resp.body)
     }
 
-    pub fn refresh_access_token(client: &mut HttpClient,
+    pub fn refresh_access_token(transport: &mut HttpTransport,
+                                endpoints: &PsnEndpoints,
                                 refresh_token: &str)
-                                -> Result<(String, String), PsnError> {
+                                -> Result<Tokens, PsnError> {
         info!("Refreshing access token from PSN");
-        let url = format!("{}/2.0/oauth/token", BASE_URL);
+        let url = format!("{}/2.0/oauth/token", endpoints.auth);
         let data = make_url_query(&[("grant_type", "refresh_token"),
                                     ("client_id", CLIENT_ID2),
                                     ("client_secret", CLIENT_SECRET2),
@@ -368,19 +535,13 @@ impl PsnPresenceProvider {
         headers.set(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![])));
         headers.set(ContentLength(data.len() as u64));
 
-        let req = client.post(&url)
-            .headers(headers)
-            .body(&data);
-
-        let mut resp = req.send()?;
-        let mut resp_body = String::new();
-        resp.read_to_string(&mut resp_body)?;
-        debug!("{}", resp_body);
+        let resp = transport.send(HttpRequest::post(&url, headers, data))?;
+        debug!("{}", resp.body);
 
-        PsnPresenceProvider::unpack_authorization(&resp_body)
+        PsnPresenceProvider::unpack_authorization(&resp.body)
     }
 
-    fn unpack_authorization(json: &str) -> Result<(String, String), PsnError> {
+    fn unpack_authorization(json: &str) -> Result<Tokens, PsnError> {
         let authorization: responses::Authorization = serde_json::from_str(json)?;
 
         match authorization.error_code {
@@ -390,42 +551,64 @@ impl PsnPresenceProvider {
                                       .unwrap_or("Unknown error".to_owned())))
             }
             None => {
-                Ok((authorization.access_token.ok_or(PsnError::MissingField("access_token"))?,
-                    authorization.refresh_token.ok_or(PsnError::MissingField("refresh_token"))?))
+                Ok(Tokens {
+                    access_token: authorization.access_token
+                        .ok_or(PsnError::MissingField("access_token"))?,
+                    refresh_token: authorization.refresh_token
+                        .ok_or(PsnError::MissingField("refresh_token"))?,
+                    expires_in: authorization.expires_in
+                        .and_then(|x| if x > 0 { Some(x as u64) } else { None })
+                        .map(Duration::from_secs),
+                })
             }
         }
     }
 
     pub fn perform_login(username: &str,
-                         password: &str)
-                         -> Result<(String, String), Box<error::Error>> {
-        let mut client = HttpClient::new();
-        client.set_redirect_policy(hyper::client::RedirectPolicy::FollowNone);
-        let cookies = PsnPresenceProvider::request_ssocookie(&mut client, &username, &password)?;
-        let _ = PsnPresenceProvider::exchange_ssocookie_for_access_token(&mut client, &cookies)?;
-        let login_code = PsnPresenceProvider::request_login_code(&mut client, &cookies)?;
+                         password: &str,
+                         endpoints: &PsnEndpoints,
+                         transport: &mut HttpTransport)
+                         -> Result<Tokens, Box<error::Error>> {
+        let cookies =
+            PsnPresenceProvider::request_ssocookie(transport, endpoints, &username, &password)?;
+        let _ = PsnPresenceProvider::exchange_ssocookie_for_access_token(transport,
+                                                                         endpoints,
+                                                                         &cookies)?;
+        let login_code = PsnPresenceProvider::request_login_code(transport, endpoints, &cookies)?;
         debug!("login_code: {}", login_code);
-        let token_pair =
-            PsnPresenceProvider::request_full_token(&mut client, &cookies, &login_code)?;
-        Ok(token_pair)
+        let tokens =
+            PsnPresenceProvider::request_full_token(transport, endpoints, &cookies, &login_code)?;
+        Ok(tokens)
     }
 }
 
 impl PresenceProvider for PsnPresenceProvider {
     fn provider_type(&self) -> PresenceProviderType {
         PresenceProviderType {
-            id: TypeId::of::<Self>(),
             name: "psn",
+            label: self.label.clone(),
         }
     }
 
+    fn backoff_policy(&self) -> BackoffPolicy {
+        self.backoff
+    }
+
     fn get_presence(&mut self) -> Result<Presence, Box<error::Error>> {
+        if self.access_token_expiring() {
+            self.refresh()?;
+        }
+
+        // only a rejected access token is worth a refresh, since every refresh
+        // rotates the refresh token as well
         let profile = match self.get_profile() {
             Ok(p) => p,
-            Err(_) => {
+            Err(PsnError::Unauthorized(_)) => {
+                info!("PSN access token was rejected, refreshing");
                 self.refresh()?;
                 self.get_profile()?
             }
+            Err(e) => return Err(Box::new(e)),
         };
 
         match profile.presences.iter().find(|ref x| x.online_status == "online") {
@@ -434,9 +617,264 @@ impl PresenceProvider for PsnPresenceProvider {
                 Ok(Some(PresenceDetail {
                     device: p.platform.clone().ok_or(PsnError::MissingField("platform"))?,
                     game: p.title_name.clone().ok_or(PsnError::MissingField("title_name"))?,
-                    extended_info: None,
+                    title_id: p.np_title_id.clone(),
+                    // PSN's closest thing to Xbox rich presence, only set by some titles
+                    extended_info: p.game_status
+                        .as_ref()
+                        .map(|x| x.trim().to_owned())
+                        .and_then(|x| if x.is_empty() { None } else { Some(x) }),
+                    kind: ActivityKind::Playing,
                 }))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use super::token_store::TokenStore;
+    use http::ReplayTransport;
+    use http::testing::{fixture_dir, fixture_dir_with_headers, temp_dir};
+
+    const TOKENS: &'static str = r#"{"access_token": "access-1", "refresh_token": "refresh-2",
+                                     "expires_in": 3600}"#;
+    const PROFILE: &'static str = r#"{"profile": {"presences": [
+                                      {"onlineStatus": "online", "platform": "PS4",
+                                       "titleName": "Bloodborne", "npTitleId": "CUSA00207_00",
+                                       "gameStatus": " Hunter's Dream "}]}}"#;
+
+    fn provider(store: &PathBuf) -> PsnPresenceProvider {
+        PsnPresenceProvider::new("main",
+                                 "someone",
+                                 "refresh-1",
+                                 PsnEndpoints::default(),
+                                 Some(TokenStore::new(store.clone())),
+                                 BackoffPolicy::default(),
+                                 Box::new(ReplayTransport::new(PathBuf::new())))
+    }
+
+    #[test]
+    fn refreshes_then_reads_presence() {
+        let state = temp_dir("psn-state");
+        let store = state.join("tokens.json");
+        let mut provider = provider(&store);
+        let token_url = format!("{}/2.0/oauth/token", DEFAULT_AUTH_URL);
+        let dir = fixture_dir("psn-refresh",
+                              &[("POST", &token_url[..], 200, TOKENS),
+                                ("GET", &provider.profile_url()[..], 200, PROFILE)]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        let detail = provider.get_presence().unwrap().unwrap();
+        assert_eq!(detail.device, "PS4");
+        assert_eq!(detail.game, "Bloodborne");
+        assert_eq!(detail.title_id, Some("CUSA00207_00".to_owned()));
+        assert_eq!(detail.extended_info, Some("Hunter's Dream".to_owned()));
+
+        // the rotated token is kept for the next start
+        assert_eq!(TokenStore::new(store).load("someone", "refresh-1").unwrap(),
+                   Some("refresh-2".to_owned()));
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    /// A provider holding an access token that expires in `expires_secs`.
+    fn authorized(store: &PathBuf, expires_secs: u64) -> PsnPresenceProvider {
+        let mut provider = provider(store);
+        provider.access_token = "access-0".to_owned();
+        provider.access_expires = Some(Instant::now() + Duration::from_secs(expires_secs));
+        provider
+    }
+
+    fn assert_exhausted(provider: &mut PsnPresenceProvider) {
+        match provider.transport.send(HttpRequest::get("http://example.com/", Headers::new())) {
+            Err(e) => assert!(e.to_string().contains("No recorded response left"), "{}", e),
+            Ok(_) => panic!("recorded responses left over"),
+        }
+    }
+
+    fn stored_token(store: &PathBuf) -> Option<String> {
+        TokenStore::new(store.clone()).load("someone", "refresh-1").unwrap()
+    }
+
+    #[test]
+    fn rejected_access_token_refreshes_and_retries_once() {
+        let state = temp_dir("psn-state-401");
+        let store = state.join("tokens.json");
+        let mut provider = authorized(&store, 3600);
+        let token_url = format!("{}/2.0/oauth/token", DEFAULT_AUTH_URL);
+        let profile_url = provider.profile_url();
+        let dir = fixture_dir("psn-401",
+                              &[("GET", &profile_url[..], 401, ""),
+                                ("POST", &token_url[..], 200, TOKENS),
+                                ("GET", &profile_url[..], 200, PROFILE)]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert_eq!(provider.get_presence().unwrap().unwrap().game, "Bloodborne");
+        assert_eq!(provider.access_token, "access-1");
+        assert_eq!(stored_token(&store), Some("refresh-2".to_owned()));
+        assert_exhausted(&mut provider);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn second_rejection_is_not_retried() {
+        let state = temp_dir("psn-state-401-twice");
+        let mut provider = authorized(&state.join("tokens.json"), 3600);
+        let token_url = format!("{}/2.0/oauth/token", DEFAULT_AUTH_URL);
+        let profile_url = provider.profile_url();
+        let dir = fixture_dir("psn-401-twice",
+                              &[("GET", &profile_url[..], 401, ""),
+                                ("POST", &token_url[..], 200, TOKENS),
+                                ("GET", &profile_url[..], 401, "")]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert!(provider.get_presence().is_err());
+        assert_exhausted(&mut provider);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn server_error_keeps_the_token() {
+        let state = temp_dir("psn-state-500");
+        let store = state.join("tokens.json");
+        let mut provider = authorized(&store, 3600);
+        let dir = fixture_dir("psn-500", &[("GET", &provider.profile_url()[..], 500, "")]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert!(provider.get_presence().is_err());
+        assert_eq!(provider.refresh_token, "refresh-1");
+        assert_eq!(provider.access_token, "access-0");
+        assert_eq!(stored_token(&store), None);
+        assert_exhausted(&mut provider);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn transport_error_keeps_the_token() {
+        let state = temp_dir("psn-state-io");
+        let store = state.join("tokens.json");
+        let mut provider = authorized(&store, 3600);
+        let dir = fixture_dir("psn-io", &[]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert!(provider.get_presence().is_err());
+        assert_eq!(provider.refresh_token, "refresh-1");
+        assert_eq!(stored_token(&store), None);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn refreshes_ahead_of_expiry() {
+        let state = temp_dir("psn-state-expiring");
+        let store = state.join("tokens.json");
+        let mut provider = authorized(&store, REFRESH_MARGIN_SECS / 2);
+        let token_url = format!("{}/2.0/oauth/token", DEFAULT_AUTH_URL);
+        let dir = fixture_dir("psn-expiring",
+                              &[("POST", &token_url[..], 200, TOKENS),
+                                ("GET", &provider.profile_url()[..], 200, PROFILE)]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert!(provider.get_presence().unwrap().is_some());
+        assert_eq!(provider.access_token, "access-1");
+        assert_exhausted(&mut provider);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn valid_token_is_not_refreshed() {
+        let state = temp_dir("psn-state-valid");
+        let mut provider = authorized(&state.join("tokens.json"), REFRESH_MARGIN_SECS * 10);
+        let dir = fixture_dir("psn-valid", &[("GET", &provider.profile_url()[..], 200, PROFILE)]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        assert!(provider.get_presence().unwrap().is_some());
+        assert_eq!(provider.access_token, "access-0");
+        assert_exhausted(&mut provider);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn picks_up_token_rotated_by_another_provider() {
+        let state = temp_dir("psn-state-rotated");
+        let store = state.join("tokens.json");
+        let mut provider = provider(&store);
+        TokenStore::new(store.clone()).save("someone", "refresh-1", "refresh-3").unwrap();
+
+        provider.load_stored_token();
+        assert_eq!(provider.refresh_token, "refresh-3");
+
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn reports_refresh_errors() {
+        let state = temp_dir("psn-state-error");
+        let mut provider = provider(&state.join("tokens.json"));
+        let token_url = format!("{}/2.0/oauth/token", DEFAULT_AUTH_URL);
+        let dir = fixture_dir("psn-refresh-error",
+                              &[("POST",
+                                 &token_url[..],
+                                 400,
+                                 r#"{"error_code": 4159, "error_description": "Invalid token"}"#)]);
+        provider.transport = Box::new(ReplayTransport::new(dir.clone()));
+
+        match provider.refresh() {
+            Err(PsnError::Api(4159, _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn logs_in_with_cookies_and_redirect() {
+        let auth = DEFAULT_AUTH_URL;
+        let sso_url = format!("{}/2.0/ssocookie", auth);
+        let token_url = format!("{}/2.0/oauth/token", auth);
+        let code_url = PsnPresenceProvider::login_code_url(&PsnEndpoints::default());
+        let dir = fixture_dir_with_headers(
+            "psn-login",
+            &[("POST",
+               &sso_url[..],
+               200,
+               &[("Set-Cookie", "npsso=abc; Expires=Wed, 21 Oct 2026 07:28:00 GMT; Path=/"),
+                 ("Set-Cookie", "other=1; Path=/")],
+               "{}"),
+              ("POST", &token_url[..], 200, &[], r#"{"access_token": "sso-access"}"#),
+              ("GET",
+               &code_url[..],
+               302,
+               &[("Location", "com.scee.psxandroid.scecompcall://redirect/?code=Ab12Cd&cid=x")],
+               ""),
+              ("POST", &token_url[..], 200, &[], TOKENS)]);
+        let mut transport = ReplayTransport::new(dir.clone());
+
+        let tokens = PsnPresenceProvider::perform_login("someone",
+                                                        "password",
+                                                        &PsnEndpoints::default(),
+                                                        &mut transport)
+            .unwrap();
+        assert_eq!(tokens.access_token, "access-1");
+        assert_eq!(tokens.refresh_token, "refresh-2");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -33,6 +33,10 @@ pub struct Presence {
     pub platform: Option<String>,
     #[serde(rename = "titleName")]
     pub title_name: Option<String>,
+    #[serde(rename = "npTitleId")]
+    pub np_title_id: Option<String>,
+    #[serde(rename = "gameStatus")]
+    pub game_status: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde_json;
+use private_file;
+
+lazy_static! {
+    // several accounts can share one store file, and each save rewrites it
+    static ref SAVE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredToken {
+    refresh_token: String,
+    /// The token from the config file that this one was rotated from. If the
+    /// config file gets a new token the stored one is stale and ignored.
+    config_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenFile {
+    tokens: BTreeMap<String, StoredToken>,
+}
+
+/// Keeps the latest PSN refresh token for each account in a state file, since
+/// PSN invalidates the old token every time it hands out a new one.
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+fn invalid_data(err: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl TokenStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> TokenStore {
+        TokenStore { path: path.into() }
+    }
+
+    fn read(&self) -> io::Result<TokenFile> {
+        match File::open(&self.path) {
+            Ok(file) => serde_json::from_reader(file).map_err(invalid_data),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(TokenFile { tokens: BTreeMap::new() })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the stored token for `psn_id`, unless it was rotated from a
+    /// different token than the one now in the config file.
+    pub fn load(&self, psn_id: &str, config_token: &str) -> io::Result<Option<String>> {
+        let file = self.read()?;
+        Ok(file.tokens
+            .get(psn_id)
+            .and_then(|x| if x.config_token == config_token {
+                Some(x.refresh_token.clone())
+            } else {
+                None
+            }))
+    }
+
+    /// Writes to a temporary file next to the store and renames it into place,
+    /// so a crash leaves either the old or the new file but never a partial one.
+    pub fn save(&self, psn_id: &str, config_token: &str, refresh_token: &str) -> io::Result<()> {
+        let _lock = SAVE_LOCK.lock().unwrap();
+        // an unreadable file may still hold other accounts' tokens, so don't
+        // replace it
+        let mut file = self.read()?;
+        file.tokens.insert(psn_id.to_owned(),
+                           StoredToken {
+                               refresh_token: refresh_token.to_owned(),
+                               config_token: config_token.to_owned(),
+                           });
+        let json = serde_json::to_string_pretty(&file).map_err(invalid_data)?;
+
+        // the default location is a state directory that may not exist yet
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let _ = fs::remove_file(&tmp_path);
+        {
+            let mut tmp = private_file::create(&tmp_path)?;
+            tmp.write_all(json.as_bytes())?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        debug!("Saved PSN refresh token for {} to {}",
+               psn_id,
+               self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use super::TokenStore;
+    use http::testing::temp_dir;
+
+    #[test]
+    fn keeps_tokens_of_other_accounts() {
+        let dir = temp_dir("token-store");
+        let store = TokenStore::new(dir.join("tokens.json"));
+        store.save("first", "config-1", "rotated-1").unwrap();
+        store.save("second", "config-2", "rotated-2").unwrap();
+
+        assert_eq!(store.load("first", "config-1").unwrap(), Some("rotated-1".to_owned()));
+        assert_eq!(store.load("second", "config-2").unwrap(), Some("rotated-2".to_owned()));
+        // a new token in the config file wins over the stored one
+        assert_eq!(store.load("first", "config-3").unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn does_not_replace_unreadable_store() {
+        let dir = temp_dir("token-store-corrupt");
+        let path = dir.join("tokens.json");
+        File::create(&path).unwrap().write_all(b"{\"tokens\": ").unwrap();
+
+        let store = TokenStore::new(path.clone());
+        assert!(store.load("first", "config-1").is_err());
+        assert!(store.save("first", "config-1", "rotated-1").is_err());
+
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "{\"tokens\": ");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+mod detail {
+    extern crate nix;
+
+    use std::io;
+    use self::nix::sys::socket::{self, AddressFamily, MsgFlags, SockAddr, SockType, UnixAddr};
+    use self::nix::unistd;
+
+    pub fn is_own_pid(pid: u32) -> bool {
+        unistd::getpid() as u32 == pid
+    }
+
+    #[cfg(target_os = "linux")]
+    fn address(path: &str) -> io::Result<UnixAddr> {
+        // a leading '@' stands for the NUL byte of an abstract socket name
+        if path.starts_with('@') {
+            Ok(UnixAddr::new_abstract(path[1..].as_bytes())?)
+        } else {
+            Ok(UnixAddr::new(path)?)
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn address(path: &str) -> io::Result<UnixAddr> {
+        Ok(UnixAddr::new(path)?)
+    }
+
+    pub fn send(path: &str, message: &str) -> io::Result<()> {
+        let address = SockAddr::Unix(address(path)?);
+        let fd = socket::socket(AddressFamily::Unix, SockType::Datagram, socket::SOCK_CLOEXEC, 0)?;
+        let result = socket::sendto(fd, message.as_bytes(), &address, MsgFlags::empty());
+        let _ = unistd::close(fd);
+        result?;
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod detail {
+    use std::io;
+
+    pub fn is_own_pid(_: u32) -> bool {
+        true
+    }
+
+    pub fn send(_: &str, _: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "service notifications need unix sockets"))
+    }
+}
+
+/// Reports readiness and watchdog pings to systemd using the sd_notify
+/// datagram protocol. Does nothing unless the service manager passed a socket
+/// in `NOTIFY_SOCKET`.
+pub struct Notifier {
+    socket: Option<String>,
+    watchdog: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    /// Reads `NOTIFY_SOCKET`, plus `WATCHDOG_USEC` unless `WATCHDOG_PID`
+    /// names a different process.
+    pub fn from_env() -> Notifier {
+        let socket = env::var("NOTIFY_SOCKET").ok().and_then(|x| if x.is_empty() {
+            None
+        } else {
+            Some(x)
+        });
+
+        let for_us = match env::var("WATCHDOG_PID").ok().and_then(|x| x.parse::<u32>().ok()) {
+            Some(pid) => detail::is_own_pid(pid),
+            None => true,
+        };
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok())
+            .and_then(|x| if for_us && x > 0 {
+                Some(Duration::from_millis(x / 1000))
+            } else {
+                None
+            });
+
+        Notifier::new(socket, watchdog)
+    }
+
+    /// `watchdog` is the timeout given by the service manager, not the ping
+    /// interval.
+    pub fn new(socket: Option<String>, watchdog: Option<Duration>) -> Notifier {
+        Notifier {
+            socket: socket,
+            watchdog: watchdog,
+            last_ping: Instant::now(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Pings at half the watchdog timeout, as sd_watchdog_enabled(3)
+    /// recommends.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        match (&self.socket, self.watchdog) {
+            (&Some(_), Some(timeout)) => Some(timeout / 2),
+            _ => None,
+        }
+    }
+
+    /// Sends newline separated `VARIABLE=value` assignments.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        match self.socket {
+            Some(ref path) => detail::send(path, state),
+            None => Ok(()),
+        }
+    }
+
+    fn notify_or_log(&self, state: &str) {
+        if let Err(e) = self.notify(state) {
+            warn!("Failed to notify the service manager ({}): {}", state, e);
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify_or_log("READY=1");
+    }
+
+    pub fn reloading(&self) {
+        self.notify_or_log("RELOADING=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify_or_log("STOPPING=1");
+    }
+
+    /// Sends a watchdog ping if one is due.
+    pub fn ping_watchdog(&mut self) {
+        if let Some(interval) = self.watchdog_interval() {
+            if self.last_ping.elapsed() >= interval {
+                self.last_ping = Instant::now();
+                self.notify_or_log("WATCHDOG=1");
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::str;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Notifier;
+    use http::testing::temp_dir;
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        str::from_utf8(&buf[..len]).unwrap().to_owned()
+    }
+
+    #[test]
+    fn sends_to_the_notify_socket() {
+        let dir = temp_dir("sd-notify");
+        let path = dir.join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut notifier = Notifier::new(Some(path.to_string_lossy().into_owned()),
+                                         Some(Duration::from_millis(40)));
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_millis(20)));
+
+        notifier.ready();
+        assert_eq!(recv(&socket), "READY=1");
+
+        thread::sleep(Duration::from_millis(30));
+        notifier.ping_watchdog();
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+
+        notifier.stopping();
+        assert_eq!(recv(&socket), "STOPPING=1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn does_nothing_without_a_socket() {
+        let notifier = Notifier::new(None, Some(Duration::from_secs(10)));
+        assert!(!notifier.is_enabled());
+        assert_eq!(notifier.watchdog_interval(), None);
+        assert!(notifier.notify("READY=1").is_ok());
+    }
+}
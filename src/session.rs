@@ -0,0 +1,436 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use backoff::{Backoff, BackoffPolicy};
+use gateway::{self, GatewayError, GatewayEvent, GatewayReader, GatewayWriter};
+
+/// How often a session waiting to reconnect checks whether it was stopped.
+const STOP_CHECK_INTERVAL_MS: u64 = 500;
+
+fn reconnect_policy() -> BackoffPolicy {
+    BackoffPolicy {
+        initial_delay: Duration::from_secs(5),
+        max_delay: Duration::from_secs(300),
+        breaker_threshold: 0,
+        probe_interval: Duration::from_secs(300),
+    }
+}
+
+/// What the next connection needs to resume the gateway session.
+struct Resume {
+    session_id: String,
+    sequence: u64,
+}
+
+/// The current gateway connection, shared by the thread reading from it and
+/// the keepalive thread writing to it.
+struct Link {
+    /// Set once Discord accepted the identify or resume.
+    ready: bool,
+    sequence: Option<u64>,
+    heartbeat_requested: bool,
+    heartbeat_acked: bool,
+    /// Set by the reading thread once the connection is lost.
+    closed: bool,
+}
+
+impl Link {
+    fn new(sequence: Option<u64>) -> Link {
+        Link {
+            ready: false,
+            sequence: sequence,
+            heartbeat_requested: false,
+            heartbeat_acked: true,
+            closed: false,
+        }
+    }
+}
+
+/// The status a session should show, shared with its threads.
+struct SessionState {
+    status: Option<String>,
+    stop: bool,
+    /// Set by the thread once it has disconnected after being stopped.
+    finished: bool,
+    link: Link,
+}
+
+struct Shared {
+    state: Mutex<SessionState>,
+    /// Wakes the keepalive thread when the status or the connection changes.
+    changed: Condvar,
+    finished: Condvar,
+}
+
+/// Keeps the gateway connection of one Discord user alive on its own thread.
+///
+/// The thread reads gateway events so a dropped websocket is noticed, and
+/// resumes the session or reconnects from scratch with backoff. A keepalive
+/// thread per connection sends heartbeats and sends a new status as soon as
+/// it is set, including the cleared status when stopping. The current status
+/// is sent again after every (re)connect.
+pub struct DiscordSession {
+    label: String,
+    shared: Arc<Shared>,
+}
+
+impl DiscordSession {
+    pub fn start(label: &str, token: &str) -> DiscordSession {
+        DiscordSession::start_with_url(label, token, gateway::DEFAULT_URL)
+    }
+
+    fn start_with_url(label: &str, token: &str, url: &str) -> DiscordSession {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SessionState {
+                status: None,
+                stop: false,
+                finished: false,
+                link: Link::new(None),
+            }),
+            changed: Condvar::new(),
+            finished: Condvar::new(),
+        });
+
+        let label_clone = label.to_owned();
+        let token = token.to_owned();
+        let url = url.to_owned();
+        let shared_clone = shared.clone();
+        thread::spawn(move || {
+            DiscordSession::run(&label_clone, &token, &url, &shared_clone);
+            shared_clone.state.lock().unwrap().finished = true;
+            shared_clone.finished.notify_all();
+        });
+
+        DiscordSession {
+            label: label.to_owned(),
+            shared: shared,
+        }
+    }
+
+    pub fn set_status(&self, status: Option<String>) {
+        self.shared.state.lock().unwrap().status = status;
+        self.shared.changed.notify_all();
+    }
+
+    /// Asks the session to clear the status and disconnect.
+    pub fn stop(&self) {
+        debug!("{} - stopping Discord session", self.label);
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.status = None;
+            state.stop = true;
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Waits until the stopped session has cleared the status and
+    /// disconnected, or until `deadline`. Returns whether it finished.
+    pub fn wait(&self, deadline: Instant) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.finished {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            state = self.shared.finished.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+
+    fn stopped(shared: &Shared) -> bool {
+        shared.state.lock().unwrap().stop
+    }
+
+    fn update_link<F: FnOnce(&mut Link)>(shared: &Shared, f: F) {
+        f(&mut shared.state.lock().unwrap().link);
+        shared.changed.notify_all();
+    }
+
+    fn run(label: &str, token: &str, url: &str, shared: &Arc<Shared>) {
+        let mut backoff = Backoff::new(reconnect_policy());
+        let mut resume = None;
+        while !DiscordSession::stopped(shared) {
+            match DiscordSession::connect(label, token, url, shared, &mut resume) {
+                Ok(true) => backoff.record_success(),
+                Ok(false) => {}
+                Err(e) => error!("{} - Discord connection failed: {}", label, e),
+            }
+
+            if DiscordSession::stopped(shared) {
+                break;
+            }
+
+            // also after a lost connection, so a connection that keeps dropping
+            // right away isn't retried in a tight loop
+            backoff.record_failure();
+            let delay = backoff.next_delay(Duration::from_secs(0));
+            info!("{} - reconnecting to Discord in {}s", label, delay.as_secs());
+            DiscordSession::sleep_unless_stopped(delay, shared);
+        }
+
+        debug!("{} - Discord session ended", label);
+    }
+
+    /// Runs one gateway connection until it is lost or the session is
+    /// stopped. Returns whether Discord accepted the session.
+    fn connect(label: &str,
+               token: &str,
+               url: &str,
+               shared: &Arc<Shared>,
+               resume: &mut Option<Resume>)
+               -> Result<bool, GatewayError> {
+        let (mut writer, mut reader) = gateway::connect(url)?;
+        let interval = match reader.recv()? {
+            GatewayEvent::Hello(interval) => interval,
+            _ => return Err(GatewayError::Protocol("expected hello")),
+        };
+
+        match *resume {
+            Some(ref r) => {
+                debug!("{} - resuming Discord session", label);
+                writer.resume(token, &r.session_id, r.sequence)?;
+            }
+            None => writer.identify(token)?,
+        }
+
+        shared.state.lock().unwrap().link = Link::new(resume.as_ref().map(|x| x.sequence));
+        let keepalive = {
+            let label = label.to_owned();
+            let shared = shared.clone();
+            thread::spawn(move || DiscordSession::keepalive(&label, writer, interval, &shared))
+        };
+
+        let accepted = DiscordSession::read_events(label, reader, shared, resume);
+        DiscordSession::update_link(shared, |link| link.closed = true);
+        let _ = keepalive.join();
+        Ok(accepted)
+    }
+
+    /// Returns once the connection is lost or the session is stopped, and
+    /// whether Discord accepted the session.
+    fn read_events(label: &str,
+                   mut reader: GatewayReader,
+                   shared: &Shared,
+                   resume: &mut Option<Resume>)
+                   -> bool {
+        let mut accepted = false;
+        loop {
+            let event = reader.recv();
+            // stopping shuts the socket down, so whatever recv returned is moot
+            if DiscordSession::stopped(shared) {
+                return accepted;
+            }
+
+            match event {
+                Ok(GatewayEvent::Dispatch(sequence, name, data)) => {
+                    if let Some(ref mut r) = *resume {
+                        r.sequence = sequence;
+                    }
+
+                    match &name[..] {
+                        "READY" => {
+                            let username = data.lookup("user.username").and_then(Value::as_str);
+                            info!("{} - Discord logged in as {}", label, username.unwrap_or("?"));
+                            *resume = data.find("session_id").and_then(Value::as_str).map(|x| {
+                                Resume {
+                                    session_id: x.to_owned(),
+                                    sequence: sequence,
+                                }
+                            });
+                            accepted = true;
+                        }
+                        "RESUMED" => {
+                            info!("{} - Discord session resumed", label);
+                            accepted = true;
+                        }
+                        _ => {}
+                    }
+
+                    DiscordSession::update_link(shared, |link| {
+                        link.sequence = Some(sequence);
+                        link.ready = accepted;
+                    });
+                }
+                Ok(GatewayEvent::Heartbeat) => {
+                    DiscordSession::update_link(shared, |link| link.heartbeat_requested = true);
+                }
+                Ok(GatewayEvent::HeartbeatAck) => {
+                    DiscordSession::update_link(shared, |link| link.heartbeat_acked = true);
+                }
+                Ok(GatewayEvent::Hello(_)) => {}
+                Ok(GatewayEvent::Reconnect) => {
+                    info!("{} - Discord asked to reconnect", label);
+                    return accepted;
+                }
+                Ok(GatewayEvent::InvalidSession) => {
+                    warn!("{} - Discord session invalidated, logging in again", label);
+                    *resume = None;
+                    return accepted;
+                }
+                Err(GatewayError::Json(e)) => {
+                    debug!("{} - ignoring undecodable Discord event: {}", label, e);
+                }
+                Err(GatewayError::Protocol(msg)) => {
+                    debug!("{} - ignoring undecodable Discord event: {}", label, msg);
+                }
+                Err(e) => {
+                    warn!("{} - Discord connection lost: {}", label, e);
+                    return accepted;
+                }
+            }
+        }
+    }
+
+    /// Sends heartbeats, and the status whenever it changes, until the
+    /// connection is lost or the session is stopped. Then shuts the socket
+    /// down, which also ends `read_events`.
+    fn keepalive(label: &str, mut writer: GatewayWriter, interval: Duration, shared: &Shared) {
+        let mut sent: Option<Option<String>> = None;
+        let mut next_heartbeat = Instant::now() + interval;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.link.closed {
+                writer.close(false);
+                return;
+            }
+
+            let now = Instant::now();
+            let heartbeat_due = now >= next_heartbeat;
+            let send_heartbeat = heartbeat_due || state.link.heartbeat_requested;
+            let send_status = state.link.ready && sent.as_ref() != Some(&state.status);
+            if !send_heartbeat && !send_status && !state.stop {
+                state = shared.changed.wait_timeout(state, next_heartbeat - now).unwrap().0;
+                continue;
+            }
+
+            if heartbeat_due && !state.link.heartbeat_acked {
+                warn!("{} - Discord stopped acknowledging heartbeats", label);
+                writer.close(false);
+                return;
+            }
+
+            let sequence = state.link.sequence;
+            let status = state.status.clone();
+            let stop = state.stop;
+            if send_heartbeat {
+                state.link.heartbeat_requested = false;
+                state.link.heartbeat_acked = false;
+                next_heartbeat = now + interval;
+            }
+            // writes can block, so don't hold up set_status meanwhile
+            drop(state);
+
+            let mut result = Ok(());
+            if send_heartbeat {
+                result = writer.heartbeat(sequence);
+            }
+            if send_status && result.is_ok() {
+                result = writer.set_game(status.as_ref().map(|x| &x[..]));
+                sent = Some(status);
+            }
+
+            if stop {
+                writer.close(true);
+                return;
+            }
+            if let Err(e) = result {
+                warn!("{} - Discord connection lost: {}", label, e);
+                writer.close(false);
+                return;
+            }
+
+            state = shared.state.lock().unwrap();
+        }
+    }
+
+    fn sleep_unless_stopped(duration: Duration, shared: &Shared) {
+        let step = Duration::from_millis(STOP_CHECK_INTERVAL_MS);
+        let mut remaining = duration;
+        while remaining > Duration::from_secs(0) && !DiscordSession::stopped(shared) {
+            let sleep = if remaining < step { remaining } else { step };
+            thread::sleep(sleep);
+            remaining -= sleep;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::{self, Value};
+    use websocket::{client, Server};
+    use websocket::message::{Message, Type};
+    use websocket::stream::WebSocketStream;
+    use websocket::ws::receiver::Receiver;
+    use websocket::ws::sender::Sender;
+
+    use super::DiscordSession;
+
+    struct FakeGateway {
+        sender: client::Sender<WebSocketStream>,
+        receiver: client::Receiver<WebSocketStream>,
+    }
+
+    impl FakeGateway {
+        fn accept(server: &mut Server) -> FakeGateway {
+            let request = server.accept().unwrap().read_request().unwrap();
+            let (sender, receiver) = request.accept().send().unwrap().split();
+            FakeGateway {
+                sender: sender,
+                receiver: receiver,
+            }
+        }
+
+        fn send(&mut self, json: &str) {
+            self.sender.send_message(&Message::text(json.to_owned())).unwrap();
+        }
+
+        /// The next message from the session, or `None` once it closed the
+        /// connection.
+        fn recv(&mut self) -> Option<Value> {
+            let message: Message = self.receiver.recv_message().unwrap();
+            match message.opcode {
+                Type::Close => None,
+                _ => Some(serde_json::from_slice(&message.payload).unwrap()),
+            }
+        }
+
+        /// The game the next message sets, which must be a presence update.
+        fn recv_game(&mut self) -> Option<String> {
+            let message = self.recv().unwrap();
+            assert_eq!(message.find("op").and_then(Value::as_u64), Some(3));
+            message.lookup("d.game.name").and_then(Value::as_str).map(|x| x.to_owned())
+        }
+    }
+
+    #[test]
+    fn sends_status_without_waiting_for_events() {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let session = DiscordSession::start_with_url("test", "token", &url);
+
+        let mut gateway = FakeGateway::accept(&mut server);
+        gateway.send(r#"{"op": 10, "d": {"heartbeat_interval": 60000}}"#);
+        let identify = gateway.recv().unwrap();
+        assert_eq!(identify.find("op").and_then(Value::as_u64), Some(2));
+        assert_eq!(identify.lookup("d.token").and_then(Value::as_str), Some("token"));
+        gateway.send(r#"{"op": 0, "s": 1, "t": "READY",
+                         "d": {"session_id": "session", "user": {"username": "someone"}}}"#);
+        assert_eq!(gateway.recv_game(), None);
+
+        // the gateway stays quiet from here on
+        session.set_status(Some("XB1: Halo 5".to_owned()));
+        assert_eq!(gateway.recv_game(), Some("XB1: Halo 5".to_owned()));
+
+        session.stop();
+        assert_eq!(gateway.recv_game(), None);
+        assert!(gateway.recv().is_none());
+        assert!(session.wait(Instant::now() + Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+struct Inner {
+    cancelled: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// Tells threads that the program is shutting down. Clones share the same
+/// state, so the token can be handed to every thread that waits or polls.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    inner: Arc<Inner>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> ShutdownToken {
+        ShutdownToken {
+            inner: Arc::new(Inner {
+                cancelled: Mutex::new(false),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Not safe to call from a signal handler; see `sigint`.
+    pub fn cancel(&self) {
+        *self.inner.cancelled.lock().unwrap() = true;
+        self.inner.condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.cancelled.lock().unwrap()
+    }
+
+    /// Wakes every waiting thread without cancelling, so it can check its own
+    /// stop conditions.
+    pub fn wake(&self) {
+        let _lock = self.inner.cancelled.lock().unwrap();
+        self.inner.condvar.notify_all();
+    }
+
+    /// Waits until the timeout passes, the token is cancelled or `wake` is
+    /// called. Can return early spuriously. Returns whether the token is
+    /// cancelled.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let cancelled = self.inner.cancelled.lock().unwrap();
+        if *cancelled {
+            return true;
+        }
+
+        *self.inner.condvar.wait_timeout(cancelled, timeout).unwrap().0
+    }
+}
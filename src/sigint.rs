@@ -1,62 +1,208 @@
-use std::sync::Condvar;
 use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 
-static mut CONDVAR: *const Condvar = 0 as *const Condvar;
-static mut IS_CANCELLED: AtomicBool = ATOMIC_BOOL_INIT;
+use shutdown::ShutdownToken;
+
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
 
 #[cfg(windows)]
 mod detail {
     extern crate winapi;
     extern crate kernel32;
 
+    use std::sync::Mutex;
     use self::winapi::{DWORD, BOOL, TRUE};
 
+    use shutdown::ShutdownToken;
+
+    lazy_static! {
+        static ref TOKEN: Mutex<Option<ShutdownToken>> = Mutex::new(None);
+    }
+
+    // console control handlers run on a thread of their own rather than
+    // interrupting one, so taking a lock here is fine
     unsafe extern "system" fn ctrlc_handler(_: DWORD) -> BOOL {
-        super::ctrlc_handler();
+        if let Some(ref token) = *TOKEN.lock().unwrap() {
+            token.cancel();
+        }
         TRUE
     }
 
-    pub fn enable_ctrlc_handler() {
+    pub fn enable_ctrlc_handler(token: &ShutdownToken) {
+        *TOKEN.lock().unwrap() = Some(token.clone());
         unsafe {
             kernel32::SetConsoleCtrlHandler(Some(ctrlc_handler), TRUE);
         }
     }
 }
 
+/// Signal handlers may only do async-signal-safe work, so SIGINT and SIGTERM
+/// write a byte to a pipe and a watcher thread cancels the token.
 #[cfg(unix)]
 mod detail {
     extern crate nix;
 
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicIsize, Ordering, ATOMIC_ISIZE_INIT};
+    use std::thread;
+    use self::nix::{c_int, Errno, Error};
+    use self::nix::fcntl;
     use self::nix::sys::signal;
+    use self::nix::unistd;
+
+    use shutdown::ShutdownToken;
 
-    extern "C" fn ctrlc_handler(_: i32) {
-        super::ctrlc_handler();
+    // set before the handlers are installed
+    static PIPE_WRITE_FD: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn errno_location() -> *mut c_int {
+        extern "C" {
+            fn __errno_location() -> *mut c_int;
+        }
+        __errno_location()
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    unsafe fn errno_location() -> *mut c_int {
+        extern "C" {
+            fn __error() -> *mut c_int;
+        }
+        __error()
+    }
+
+    #[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+    unsafe fn errno_location() -> *mut c_int {
+        extern "C" {
+            fn __errno() -> *mut c_int;
+        }
+        __errno()
+    }
+
+    // on other targets errno isn't saved, so the interrupted code may see
+    // the EAGAIN of a write to a full pipe
+    #[cfg(not(any(target_os = "linux",
+                  target_os = "android",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "freebsd",
+                  target_os = "openbsd",
+                  target_os = "netbsd")))]
+    unsafe fn errno_location() -> *mut c_int {
+        static mut UNSAVED_ERRNO: c_int = 0;
+        &mut UNSAVED_ERRNO
+    }
+
+    extern "C" fn signal_handler(signum: i32) {
+        // the interrupted code may be about to check errno
+        let saved_errno = unsafe { *errno_location() };
+
+        if signum == signal::SIGHUP as i32 {
+            super::reload_handler();
+        } else {
+            // the write end is non-blocking, so a full pipe or a dead watcher
+            // can't hang the interrupted thread; a pending byte is enough anyway
+            let fd = PIPE_WRITE_FD.load(Ordering::SeqCst) as RawFd;
+            let _ = unistd::write(fd, &[1]);
+        }
+
+        unsafe {
+            *errno_location() = saved_errno;
+        }
     }
 
-    pub fn enable_ctrlc_handler() {
-        let sig_action = signal::SigAction::new(signal::SigHandler::Handler(ctrlc_handler),
+    fn watch_pipe(fd: RawFd, token: ShutdownToken) {
+        let mut buf = [0; 16];
+        loop {
+            match unistd::read(fd, &mut buf) {
+                Ok(0) => return,
+                Ok(_) => {
+                    info!("Shutdown signal received");
+                    token.cancel();
+                }
+                Err(Error::Sys(Errno::EINTR)) => {}
+                Err(e) => {
+                    error!("Failed to read the signal pipe: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// SIGINT and SIGTERM stop the program, SIGHUP asks for a config reload.
+    pub fn enable_ctrlc_handler(token: &ShutdownToken) {
+        let (read_fd, write_fd) = unistd::pipe2(fcntl::O_CLOEXEC | fcntl::O_NONBLOCK).unwrap();
+        // only the signal handler needs non-blocking writes, the watcher blocks
+        // (F_SETFL ignores the access mode, so O_RDONLY clears every flag)
+        fcntl::fcntl(read_fd, fcntl::FcntlArg::F_SETFL(fcntl::O_RDONLY)).unwrap();
+        PIPE_WRITE_FD.store(write_fd as isize, Ordering::SeqCst);
+
+        let token = token.clone();
+        thread::spawn(move || watch_pipe(read_fd, token));
+
+        let sig_action = signal::SigAction::new(signal::SigHandler::Handler(signal_handler),
                                                 signal::SaFlags::empty(),
                                                 signal::SigSet::empty());
         unsafe {
             signal::sigaction(signal::SIGINT, &sig_action).unwrap();
+            signal::sigaction(signal::SIGTERM, &sig_action).unwrap();
+            signal::sigaction(signal::SIGHUP, &sig_action).unwrap();
         }
     }
 }
 
-fn ctrlc_handler() {
-    unsafe {
-        IS_CANCELLED.store(true, Ordering::Relaxed);
-        (*CONDVAR).notify_all();
-    }
+// the main loop polls for this, so there is nothing to wake up
+#[cfg(unix)]
+fn reload_handler() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
 }
 
-pub fn set_ctrlc_handler(condvar: &Condvar) {
-    unsafe {
-        CONDVAR = condvar as *const Condvar;
-    }
-    detail::enable_ctrlc_handler();
+/// Cancels `token` when the program is asked to stop.
+pub fn set_ctrlc_handler(token: &ShutdownToken) {
+    detail::enable_ctrlc_handler(token);
 }
 
-pub fn cancelled() -> bool {
-    unsafe { IS_CANCELLED.load(Ordering::Relaxed) }
+/// Returns whether a reload was requested (SIGHUP) since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    extern crate nix;
+
+    use std::time::{Duration, Instant};
+    use self::nix::sys::signal;
+
+    use super::*;
+    use shutdown::ShutdownToken;
+
+    lazy_static! {
+        // the handlers are process-wide, so every test shares one installation
+        static ref TOKEN: ShutdownToken = {
+            let token = ShutdownToken::new();
+            set_ctrlc_handler(&token);
+            token
+        };
+    }
+
+    #[test]
+    fn sigterm_cancels_the_token() {
+        let token = TOKEN.clone();
+        signal::raise(signal::SIGTERM).unwrap();
+
+        // wait_timeout can return early, so wait in steps up to a deadline
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !token.is_cancelled() && Instant::now() < deadline {
+            token.wait_timeout(Duration::from_millis(100));
+        }
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn sighup_requests_a_reload() {
+        let _ = TOKEN.clone();
+        signal::raise(signal::SIGHUP).unwrap();
+        assert!(take_reload_request());
+        assert!(!take_reload_request());
+    }
 }
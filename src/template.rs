@@ -0,0 +1,382 @@
+use std::str::FromStr;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TemplateError {
+        Unclosed(pos: usize) {
+            description("unclosed placeholder")
+            display("Unclosed '{{' at position {}", pos)
+        }
+        UnexpectedBrace(pos: usize) {
+            description("unexpected brace")
+            display("Unexpected '}}' at position {} (use '}}}}' for a literal brace)", pos)
+        }
+        UnknownField(name: String) {
+            description("unknown field")
+            display("Unknown field '{}'", name)
+        }
+        UnknownFilter(name: String) {
+            description("unknown filter")
+            display("Unknown filter '{}'", name)
+        }
+        InvalidFilterArgument(filter: String, arg: String) {
+            description("invalid filter argument")
+            display("Invalid argument '{}' for filter '{}'", arg, filter)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Device,
+    Game,
+    ExtendedInfo,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Result<Field, TemplateError> {
+        match name {
+            "device" => Ok(Field::Device),
+            "game" => Ok(Field::Game),
+            "extended_info" => Ok(Field::ExtendedInfo),
+            _ => Err(TemplateError::UnknownField(name.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Filter {
+    Upper,
+    Lower,
+    Title,
+    Truncate(usize),
+}
+
+impl Filter {
+    fn from_spec(spec: &str) -> Result<Filter, TemplateError> {
+        let mut split = spec.splitn(2, ':');
+        let name = split.next().unwrap().trim();
+        let arg = split.next().map(|x| x.trim());
+
+        match (name, arg) {
+            ("upper", None) => Ok(Filter::Upper),
+            ("lower", None) => Ok(Filter::Lower),
+            ("title", None) => Ok(Filter::Title),
+            ("truncate", Some(n)) => {
+                match n.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(Filter::Truncate(n)),
+                    _ => Err(TemplateError::InvalidFilterArgument(name.to_owned(), n.to_owned())),
+                }
+            }
+            ("upper", Some(a)) | ("lower", Some(a)) | ("title", Some(a)) => {
+                Err(TemplateError::InvalidFilterArgument(name.to_owned(), a.to_owned()))
+            }
+            ("truncate", None) => {
+                Err(TemplateError::InvalidFilterArgument(name.to_owned(), "".to_owned()))
+            }
+            _ => Err(TemplateError::UnknownFilter(name.to_owned())),
+        }
+    }
+
+    fn apply(&self, value: String) -> String {
+        match *self {
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Title => {
+                let mut result = String::new();
+                let mut word_start = true;
+                for c in value.chars() {
+                    if word_start {
+                        result.extend(c.to_uppercase());
+                    } else {
+                        result.extend(c.to_lowercase());
+                    }
+                    word_start = c.is_whitespace();
+                }
+                result
+            }
+            Filter::Truncate(n) => {
+                if value.chars().count() <= n {
+                    value
+                } else {
+                    let mut result: String = value.chars().take(n - 1).collect();
+                    result.push('…');
+                    result
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Field(Field, Vec<Filter>),
+    Optional(Vec<Part>),
+}
+
+/// Values a template can refer to. A field that is `None` or empty is
+/// considered missing.
+pub struct TemplateValues<'a> {
+    pub device: &'a str,
+    pub game: &'a str,
+    pub extended_info: Option<&'a str>,
+}
+
+impl<'a> TemplateValues<'a> {
+    fn get(&self, field: Field) -> Option<&'a str> {
+        let value = match field {
+            Field::Device => Some(self.device),
+            Field::Game => Some(self.game),
+            Field::ExtendedInfo => self.extended_info,
+        };
+
+        value.and_then(|x| if x.is_empty() { None } else { Some(x) })
+    }
+}
+
+/// A parsed status template.
+///
+/// `{field}` inserts a field, optionally passed through filters:
+/// `{game|upper}`, `{extended_info|truncate:20}`. `{? ...}` is an optional
+/// section that is only rendered when every field it mentions is present, e.g.
+/// `{game} on {device}{? — {extended_info}}`. `{{` and `}}` are literal braces.
+///
+/// Fields: `device`, `game`, `extended_info`.
+/// Filters: `upper`, `lower`, `title`, `truncate:N`.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+
+        Ok(Template { parts: parser.parse_parts(None)? })
+    }
+}
+
+impl Template {
+    /// Renders the template. Missing fields outside of an optional section
+    /// render as nothing. Returns `None` if the result is blank.
+    pub fn render(&self, values: &TemplateValues) -> Option<String> {
+        let mut result = String::new();
+        render_parts(&self.parts, values, &mut result);
+
+        let result = result.trim();
+        if result.is_empty() {
+            None
+        } else {
+            Some(result.to_owned())
+        }
+    }
+}
+
+fn render_parts(parts: &[Part], values: &TemplateValues, out: &mut String) -> bool {
+    let mut complete = true;
+    for part in parts {
+        match *part {
+            Part::Literal(ref s) => out.push_str(s),
+            Part::Field(field, ref filters) => {
+                match values.get(field) {
+                    Some(v) => {
+                        let value = filters.iter().fold(v.to_owned(), |acc, x| x.apply(acc));
+                        out.push_str(&value);
+                    }
+                    None => complete = false,
+                }
+            }
+            Part::Optional(ref inner) => {
+                let mut section = String::new();
+                if render_parts(inner, values, &mut section) {
+                    out.push_str(&section);
+                }
+            }
+        }
+    }
+
+    complete
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).cloned()
+    }
+
+    fn parse_parts(&mut self, optional_start: Option<usize>) -> Result<Vec<Part>, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+
+        while let Some(c) = self.peek(0) {
+            if (c == '{' || c == '}') && self.peek(1) == Some(c) {
+                literal.push(c);
+                self.pos += 2;
+                continue;
+            }
+
+            if c == '}' {
+                if optional_start.is_none() {
+                    return Err(TemplateError::UnexpectedBrace(self.pos));
+                }
+
+                self.pos += 1;
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal));
+                }
+                return Ok(parts);
+            }
+
+            if c == '{' {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal.clone()));
+                    literal.clear();
+                }
+
+                let start = self.pos;
+                self.pos += 1;
+                if self.peek(0) == Some('?') {
+                    self.pos += 1;
+                    parts.push(Part::Optional(self.parse_parts(Some(start))?));
+                } else {
+                    parts.push(self.parse_placeholder(start)?);
+                }
+                continue;
+            }
+
+            literal.push(c);
+            self.pos += 1;
+        }
+
+        if let Some(start) = optional_start {
+            return Err(TemplateError::Unclosed(start));
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(parts)
+    }
+
+    fn parse_placeholder(&mut self, start: usize) -> Result<Part, TemplateError> {
+        let mut content = String::new();
+        loop {
+            match self.peek(0) {
+                None => return Err(TemplateError::Unclosed(start)),
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('{') => return Err(TemplateError::Unclosed(start)),
+                Some(c) => {
+                    content.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        let mut specs = content.split('|');
+        let field = Field::from_name(specs.next().unwrap().trim())?;
+        let mut filters = Vec::new();
+        for spec in specs {
+            filters.push(Filter::from_spec(spec)?);
+        }
+
+        Ok(Part::Field(field, filters))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, extended_info: Option<&str>) -> Option<String> {
+        template.parse::<Template>().unwrap().render(&TemplateValues {
+            device: "XB1",
+            game: "Halo 5",
+            extended_info: extended_info,
+        })
+    }
+
+    #[test]
+    fn default_format_matches_old_output() {
+        let format = "{device}: {game}{? {extended_info}}";
+        assert_eq!(render(format, None), Some("XB1: Halo 5".to_owned()));
+        assert_eq!(render(format, Some("Arena")), Some("XB1: Halo 5 Arena".to_owned()));
+    }
+
+    #[test]
+    fn optional_section_needs_every_field() {
+        let format = "{game}{? ({extended_info} on {device})}";
+        assert_eq!(render(format, None), Some("Halo 5".to_owned()));
+        assert_eq!(render(format, Some("")), Some("Halo 5".to_owned()));
+        assert_eq!(render(format, Some("Arena")),
+                   Some("Halo 5 (Arena on XB1)".to_owned()));
+    }
+
+    #[test]
+    fn missing_field_outside_optional_section_is_empty() {
+        assert_eq!(render("{game} - {extended_info}", None),
+                   Some("Halo 5 -".to_owned()));
+        assert_eq!(render("{extended_info}", None), None);
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(render("{{{game}}}", None), Some("{Halo 5}".to_owned()));
+        assert_eq!(render("{{game}}", None), Some("{game}".to_owned()));
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(render("{game|upper}", None), Some("HALO 5".to_owned()));
+        assert_eq!(render("{game|lower}", None), Some("halo 5".to_owned()));
+        assert_eq!(render("{extended_info|title}", Some("playing ARENA")),
+                   Some("Playing Arena".to_owned()));
+        assert_eq!(render("{game | lower | upper}", None), Some("HALO 5".to_owned()));
+    }
+
+    #[test]
+    fn truncate() {
+        assert_eq!(render("{game|truncate:6}", None), Some("Halo 5".to_owned()));
+        assert_eq!(render("{game|truncate:4}", None), Some("Hal…".to_owned()));
+        assert_eq!(render("{game|truncate:1}", None), Some("…".to_owned()));
+    }
+
+    #[test]
+    fn parse_errors() {
+        match "{game".parse::<Template>() {
+            Err(TemplateError::Unclosed(0)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match "{? {game}".parse::<Template>() {
+            Err(TemplateError::Unclosed(0)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match "game}".parse::<Template>() {
+            Err(TemplateError::UnexpectedBrace(4)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match "{title}".parse::<Template>() {
+            Err(TemplateError::UnknownField(ref name)) if name == "title" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match "{game|reverse}".parse::<Template>() {
+            Err(TemplateError::UnknownFilter(ref name)) if name == "reverse" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!("{game|truncate}".parse::<Template>().is_err());
+        assert!("{game|truncate:0}".parse::<Template>().is_err());
+        assert!("{game|upper:2}".parse::<Template>().is_err());
+    }
+}
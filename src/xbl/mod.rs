@@ -1,30 +1,239 @@
 mod responses;
 
-use hyper::client::Client as HttpClient;
 use hyper::header::Headers;
-use std::io::Read;
+use std::cmp;
+use std::collections::HashMap;
 use std::iter::Iterator;
-use std::any::TypeId;
-use serde_hjson::Value as HJsonValue;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use HJsonObject;
 use PresenceProvider;
 use Presence;
 use PresenceDetail;
+use ActivityKind;
 use PresenceProviderType;
+use config::{self, ConfigError, Section};
+use http::{HttpRequest, HttpTransport, TransportFactory};
+use backoff::BackoffPolicy;
 use serde_json;
 
 use std::io;
 use std::error;
 use hyper;
+use serde_hjson::Value as HJsonValue;
 
 header! { (XAuth, "X-AUTH") => [String] }
+header! { (XRateLimitLimit, "X-RateLimit-Limit") => [u32] }
+header! { (XRateLimitRemaining, "X-RateLimit-Remaining") => [u32] }
+header! { (XRateLimitReset, "X-RateLimit-Reset") => [u64] }
+
+const DEFAULT_BASE_URL: &'static str = "https://xboxapi.com";
+const DEFAULT_DEVICE_PRIORITY: &'static [&'static str] = &["XboxOne", "Xbox360"];
+const DEFAULT_IGNORED_DEVICES: &'static [&'static str] = &["iOS", "Android"];
+const HOME_TITLE_ID: u64 = 714681658;
+const DEFAULT_SYSTEM_APPS: &'static [&'static str] = &["Home",
+                                                       "Settings",
+                                                       "Store",
+                                                       "Microsoft Store",
+                                                       "Xbox App",
+                                                       "Guide",
+                                                       "Achievements",
+                                                       "Friends",
+                                                       "Party",
+                                                       "Messages",
+                                                       "Activity feed",
+                                                       "Profile",
+                                                       "My games & apps",
+                                                       "OneGuide",
+                                                       "Game DVR"];
+const DEFAULT_MEDIA_APPS: &'static [&'static str] = &["Netflix",
+                                                      "YouTube",
+                                                      "Hulu",
+                                                      "Amazon Video",
+                                                      "Prime Video",
+                                                      "Twitch",
+                                                      "Plex",
+                                                      "Spotify",
+                                                      "HBO GO",
+                                                      "HBO NOW",
+                                                      "Crunchyroll",
+                                                      "Sling TV",
+                                                      "Movies & TV",
+                                                      "Groove Music",
+                                                      "Blu-ray Player"];
+
+/// The hourly quota of the API key as of the last response.
+struct RateLimit {
+    limit: u32,
+    remaining: u32,
+    reset_at: Instant,
+}
+
+/// xboxapi.com counts requests per API key rather than per account, so every
+/// provider using a key shares the last quota seen for it.
+type SharedRateLimit = Arc<Mutex<Option<RateLimit>>>;
+
+lazy_static! {
+    static ref RATE_LIMITS: Mutex<HashMap<String, SharedRateLimit>> = Mutex::new(HashMap::new());
+}
+
+fn shared_rate_limit(api_key: &str) -> SharedRateLimit {
+    RATE_LIMITS.lock()
+        .unwrap()
+        .entry(api_key.to_owned())
+        .or_insert_with(|| Arc::new(Mutex::new(None)))
+        .clone()
+}
+
+/// Which of the signed in devices to report, from `xbl.device_priority` and
+/// `xbl.ignored_devices`. Both use the device types reported by the API, such
+/// as "XboxOne", "Xbox360", "WindowsOneCore", "iOS" or "Android".
+pub struct DeviceSelection {
+    priority: Vec<String>,
+    ignored: Vec<String>,
+}
+
+impl Default for DeviceSelection {
+    fn default() -> DeviceSelection {
+        DeviceSelection {
+            priority: DEFAULT_DEVICE_PRIORITY.iter().map(|x| (*x).to_owned()).collect(),
+            ignored: DEFAULT_IGNORED_DEVICES.iter().map(|x| (*x).to_owned()).collect(),
+        }
+    }
+}
+
+impl DeviceSelection {
+    fn from_section(section: &Section) -> Result<DeviceSelection, ConfigError> {
+        let default = DeviceSelection::default();
+        Ok(DeviceSelection {
+            priority: section.string_array("device_priority")?.unwrap_or(default.priority),
+            ignored: section.string_array("ignored_devices")?.unwrap_or(default.ignored),
+        })
+    }
+
+    fn rank(&self, device: &str) -> usize {
+        self.priority.iter().position(|x| x == device).unwrap_or(self.priority.len())
+    }
+
+    /// Picks the foreground title of the highest ranked device that has one,
+    /// skipping system apps. Devices that are not in the priority list rank
+    /// after the listed ones, in the order the API returned them.
+    fn select<'a>(&self,
+                  devices: &'a [responses::Device],
+                  apps: &AppLists)
+                  -> Option<(&'a responses::Device, &'a responses::Title)> {
+        let mut best: Option<(&responses::Device, &responses::Title)> = None;
+        for device in devices.iter().filter(|x| !self.ignored.contains(&x.name)) {
+            let title = match device.titles
+                .iter()
+                .find(|x| {
+                    x.placement != "Background" && x.state == "Active" && !apps.is_system(x)
+                }) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            best = match best {
+                Some(b) if self.rank(&b.0.name) <= self.rank(&device.name) => Some(b),
+                _ => Some((device, title)),
+            };
+        }
+
+        best
+    }
+}
+
+enum AppMatch {
+    Name(String),
+    Id(u64),
+}
+
+impl AppMatch {
+    fn is_match(&self, title: &responses::Title) -> bool {
+        match *self {
+            AppMatch::Name(ref name) => *name == title.name.to_lowercase(),
+            AppMatch::Id(id) => title.id == Some(id),
+        }
+    }
+}
+
+/// Titles that are not games. System apps (the dashboard, settings, the
+/// store) are never reported; media apps are reported as "Watching" through
+/// `media_format`. `xbl.system_apps` and `xbl.media_apps` add to the built-in
+/// lists; entries are title names (case insensitive) or numeric title IDs.
+pub struct AppLists {
+    system: Vec<AppMatch>,
+    media: Vec<AppMatch>,
+}
+
+fn builtin_apps(names: &[&str]) -> Vec<AppMatch> {
+    names.iter().map(|x| AppMatch::Name(x.to_lowercase())).collect()
+}
+
+fn read_apps(section: &Section, key: &str, apps: &mut Vec<AppMatch>) -> Result<(), ConfigError> {
+    let array = match section.array(key)? {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+
+    for (i, value) in array.iter().enumerate() {
+        let app = match *value {
+            HJsonValue::String(ref s) => AppMatch::Name(s.to_lowercase()),
+            HJsonValue::U64(n) => AppMatch::Id(n),
+            HJsonValue::I64(n) if n >= 0 => AppMatch::Id(n as u64),
+            HJsonValue::F64(n) if n >= 0.0 && n.fract() == 0.0 => AppMatch::Id(n as u64),
+            _ => {
+                return Err(ConfigError::InvalidValue(format!("{}[{}]", section.key_path(key), i),
+                                                     "expected a title name or title ID"
+                                                         .to_owned()))
+            }
+        };
+        apps.push(app);
+    }
+
+    Ok(())
+}
+
+impl Default for AppLists {
+    fn default() -> AppLists {
+        let mut system = builtin_apps(DEFAULT_SYSTEM_APPS);
+        system.push(AppMatch::Id(HOME_TITLE_ID));
+
+        AppLists {
+            system: system,
+            media: builtin_apps(DEFAULT_MEDIA_APPS),
+        }
+    }
+}
+
+impl AppLists {
+    fn from_section(section: &Section) -> Result<AppLists, ConfigError> {
+        let mut apps = AppLists::default();
+        read_apps(section, "system_apps", &mut apps.system)?;
+        read_apps(section, "media_apps", &mut apps.media)?;
+        Ok(apps)
+    }
 
-const BASE_URL: &'static str = "https://xboxapi.com";
+    fn is_system(&self, title: &responses::Title) -> bool {
+        self.system.iter().any(|x| x.is_match(title))
+    }
+
+    fn is_media(&self, title: &responses::Title) -> bool {
+        self.media.iter().any(|x| x.is_match(title))
+    }
+}
 
 pub struct XblPresenceProvider {
+    label: String,
     xbl_id: String,
     api_key: String,
+    base_url: String,
+    devices: DeviceSelection,
+    apps: AppLists,
+    backoff: BackoffPolicy,
+    rate_limit: SharedRateLimit,
+    transport: Box<HttpTransport>,
 }
 
 quick_error! {
@@ -60,23 +269,18 @@ quick_error! {
 }
 
 impl XblPresenceProvider {
-    fn get(&self, url: &str) -> Result<responses::Presence, XblError> {
+    fn get(&mut self, url: &str) -> Result<responses::Presence, XblError> {
         info!("Requesting data from Xbox API");
 
         let mut headers = Headers::new();
         headers.set(XAuth(self.api_key.clone()));
 
-        let client = HttpClient::new();
-        let req = client.get(url);
-        let req = req.headers(headers);
-
-        let mut resp = req.send()?;
-        let mut json = String::new();
-        resp.read_to_string(&mut json)?;
+        let resp = self.transport.send(HttpRequest::get(url, headers))?;
 
-        debug!("Xbox API response: {}", json);
+        debug!("Xbox API response: {}", resp.body);
+        self.update_rate_limit(&resp.headers);
 
-        let presence: responses::Presence = serde_json::from_str(&json)?;
+        let presence: responses::Presence = serde_json::from_str(&resp.body)?;
         match presence.error_code {
             None => Ok(presence),
             Some(e) => {
@@ -86,32 +290,137 @@ impl XblPresenceProvider {
         }
     }
 
-    pub fn new(xbl_id: &str, api_key: &str) -> XblPresenceProvider {
+    /// xboxapi.com reports the quota as `X-RateLimit-Limit`,
+    /// `X-RateLimit-Remaining` and `X-RateLimit-Reset` (seconds until the
+    /// quota resets).
+    fn update_rate_limit(&mut self, headers: &Headers) {
+        let limit = headers.get::<XRateLimitLimit>().map(|x| x.0);
+        let remaining = headers.get::<XRateLimitRemaining>().map(|x| x.0);
+        let reset = headers.get::<XRateLimitReset>().map(|x| x.0);
+
+        *self.rate_limit.lock().unwrap() = match (limit, remaining, reset) {
+            (Some(limit), Some(remaining), Some(reset)) => {
+                info!("Xbox API quota: {} of {} requests left, resets in {}s",
+                      remaining,
+                      limit,
+                      reset);
+                Some(RateLimit {
+                    limit: limit,
+                    remaining: remaining,
+                    reset_at: Instant::now() + Duration::from_secs(reset),
+                })
+            }
+            _ => None,
+        };
+    }
+
+    pub fn new(label: &str,
+               xbl_id: &str,
+               api_key: &str,
+               base_url: &str,
+               devices: DeviceSelection,
+               apps: AppLists,
+               backoff: BackoffPolicy,
+               transport: Box<HttpTransport>)
+               -> XblPresenceProvider {
         XblPresenceProvider {
+            label: label.to_owned(),
             xbl_id: xbl_id.to_owned(),
             api_key: api_key.to_owned(),
+            base_url: base_url.to_owned(),
+            devices: devices,
+            apps: apps,
+            backoff: backoff,
+            rate_limit: shared_rate_limit(api_key),
+            transport: transport,
         }
     }
 
-    pub fn from_config(config: &HJsonObject) -> Option<XblPresenceProvider> {
-        let xbl_obj = json!(opt!(config.get("xbl")), HJsonValue::Object);
-        let id = json!(opt!(xbl_obj.get("id")), HJsonValue::String);
-        let api_key = json!(opt!(xbl_obj.get("api_key")), HJsonValue::String);
+    /// `xbl` is either one account object or an array of them.
+    pub fn from_config(config: &HJsonObject,
+                       transports: &TransportFactory)
+                       -> Result<Vec<XblPresenceProvider>, ConfigError> {
+        let mut providers = Vec::new();
+        let mut labels = Vec::new();
+        for section in Section::root(config).objects("xbl")? {
+            section.check_keys(&["id",
+                                 "label",
+                                 "api_key",
+                                 "device_priority",
+                                 "ignored_devices",
+                                 "system_apps",
+                                 "media_apps",
+                                 "endpoints",
+                                 "backoff"])?;
+            let id = section.required_string("id")?;
+            let label = config::read_label(&section, id, &mut labels)?;
+            let api_key = section.required_string("api_key")?;
+            let endpoints = config::read_endpoints(&section, &[("api", DEFAULT_BASE_URL)])?;
+            let devices = DeviceSelection::from_section(&section)?;
+            let apps = AppLists::from_section(&section)?;
+            let backoff = BackoffPolicy::from_section(&section)?;
+            let transport = transports.create(&format!("xbl-{}", label));
 
-        Some(XblPresenceProvider::new(&id, &api_key))
+            providers.push(XblPresenceProvider::new(&label,
+                                                    id,
+                                                    api_key,
+                                                    &endpoints["api"],
+                                                    devices,
+                                                    apps,
+                                                    backoff,
+                                                    transport));
+        }
+
+        Ok(providers)
     }
 }
 
 impl PresenceProvider for XblPresenceProvider {
     fn provider_type(&self) -> PresenceProviderType {
         PresenceProviderType {
-            id: TypeId::of::<Self>(),
             name: "xbl",
+            label: self.label.clone(),
         }
     }
 
+    fn backoff_policy(&self) -> BackoffPolicy {
+        self.backoff
+    }
+
+    /// Spreads the remaining quota evenly over the time left until it resets
+    /// and over every provider using the same API key.
+    fn min_update_interval(&self) -> Option<Duration> {
+        // RATE_LIMITS holds one reference, every live provider on the key another
+        let sharers = (Arc::strong_count(&self.rate_limit) - 1) as u32;
+        let rate_limit = self.rate_limit.lock().unwrap();
+        let rate_limit = match *rate_limit {
+            Some(ref r) => r,
+            None => return None,
+        };
+
+        let now = Instant::now();
+        if now >= rate_limit.reset_at {
+            return None;
+        }
+
+        let until_reset = rate_limit.reset_at - now;
+        let interval = if rate_limit.remaining == 0 {
+            until_reset + Duration::from_secs(1)
+        } else {
+            until_reset / rate_limit.remaining * cmp::max(sharers, 1)
+        };
+
+        if rate_limit.remaining < rate_limit.limit / 10 {
+            warn!("Xbox API quota almost used up ({} left), polling at most every {}s",
+                  rate_limit.remaining,
+                  interval.as_secs());
+        }
+
+        Some(interval)
+    }
+
     fn get_presence(&mut self) -> Result<Presence, Box<error::Error>> {
-        let presence_url = format!("{}/v2/{}/presence", BASE_URL, self.xbl_id);
+        let presence_url = format!("{}/v2/{}/presence", self.base_url, self.xbl_id);
         let resp = self.get(&presence_url)?;
 
         let devices = resp.devices.unwrap_or(Vec::new());
@@ -119,10 +428,7 @@ impl PresenceProvider for XblPresenceProvider {
             return Ok(None);
         }
 
-        let ref device = devices[0];
-        let ref titles = device.titles;
-        let title = match titles.into_iter()
-            .find(|ref x| x.placement != "Background" && x.state == "Active") {
+        let (device, title) = match self.devices.select(&devices, &self.apps) {
             Some(s) => s,
             None => return Ok(None),
         };
@@ -146,7 +452,199 @@ impl PresenceProvider for XblPresenceProvider {
         Ok(Some(PresenceDetail {
             device: device_type,
             game: title.name.clone(),
+            title_id: title.id.map(|x| x.to_string()),
             extended_info: rich_presence,
+            kind: if self.apps.is_media(title) {
+                ActivityKind::Watching
+            } else {
+                ActivityKind::Playing
+            },
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use http::ReplayTransport;
+    use http::testing::fixture_dir;
+
+    const PRESENCE_URL: &'static str = "https://xboxapi.com/v2/2533274800000000/presence";
+
+    fn provider(dir: PathBuf) -> XblPresenceProvider {
+        keyed_provider("key", dir)
+    }
+
+    fn keyed_provider(api_key: &str, dir: PathBuf) -> XblPresenceProvider {
+        XblPresenceProvider::new("main",
+                                 "2533274800000000",
+                                 api_key,
+                                 DEFAULT_BASE_URL,
+                                 DeviceSelection::default(),
+                                 AppLists::default(),
+                                 BackoffPolicy::default(),
+                                 Box::new(ReplayTransport::new(dir)))
+    }
+
+    fn replay(name: &str, body: &str) -> Result<Presence, Box<error::Error>> {
+        let dir = fixture_dir(name, &[("GET", PRESENCE_URL, 200, body)]);
+        let result = provider(dir.clone()).get_presence();
+        fs::remove_dir_all(dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn reports_foreground_title() {
+        let detail = replay("xbl-playing",
+                            r#"{"state": "Online", "devices": [{"type": "XboxOne", "titles": [
+                                {"id": 714681658, "name": "Home", "placement": "Background",
+                                 "state": "Active"},
+                                {"id": 219630713, "name": "Halo 5: Guardians",
+                                 "placement": "Full", "state": "Active",
+                                 "activity": {"richPresence": "Playing Arena"}}]}]}"#)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(detail.device, "XB1");
+        assert_eq!(detail.game, "Halo 5: Guardians");
+        assert_eq!(detail.title_id, Some("219630713".to_owned()));
+        assert_eq!(detail.extended_info, Some("Playing Arena".to_owned()));
+        assert_eq!(detail.kind, ActivityKind::Playing);
+    }
+
+    fn devices(priority: &[&str], ignored: &[&str]) -> DeviceSelection {
+        DeviceSelection {
+            priority: priority.iter().map(|x| (*x).to_owned()).collect(),
+            ignored: ignored.iter().map(|x| (*x).to_owned()).collect(),
+        }
+    }
+
+    /// The device and game reported for `devices_json`, the API's device list.
+    fn select(name: &str,
+              selection: DeviceSelection,
+              devices_json: &str)
+              -> Option<(String, String)> {
+        let body = format!(r#"{{"state": "Online", "devices": {}}}"#, devices_json);
+        let dir = fixture_dir(name, &[("GET", PRESENCE_URL, 200, &body[..])]);
+        let mut provider = provider(dir.clone());
+        provider.devices = selection;
+        let presence = provider.get_presence().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        presence.map(|x| (x.device, x.game))
+    }
+
+    fn shown(device: &str, game: &str) -> Option<(String, String)> {
+        Some((device.to_owned(), game.to_owned()))
+    }
+
+    const CONSOLE_ON_HOME: &'static str =
+        r#"{"type": "XboxOne", "titles": [{"id": 714681658, "name": "Home",
+                                           "placement": "Full", "state": "Active"}]}"#;
+
+    #[test]
+    fn lower_priority_device_shows_when_console_is_on_home() {
+        let devices_json = format!(r#"[{}, {{"type": "Xbox360", "titles": [
+            {{"id": 1, "name": "Halo 3", "placement": "Full", "state": "Active"}}]}}]"#,
+                                   CONSOLE_ON_HOME);
+        assert_eq!(select("xbl-home-and-360", DeviceSelection::default(), &devices_json),
+                   shown("360", "Halo 3"));
+    }
+
+    #[test]
+    fn higher_priority_device_wins() {
+        let devices_json = r#"[
+            {"type": "Xbox360", "titles": [{"id": 1, "name": "Halo 3", "placement": "Full",
+                                            "state": "Active"}]},
+            {"type": "XboxOne", "titles": [{"id": 2, "name": "Halo 5", "placement": "Full",
+                                            "state": "Active"}]}]"#;
+        assert_eq!(select("xbl-priority", DeviceSelection::default(), devices_json),
+                   shown("XB1", "Halo 5"));
+        assert_eq!(select("xbl-priority-custom", devices(&["Xbox360"], &[]), devices_json),
+                   shown("360", "Halo 3"));
+    }
+
+    #[test]
+    fn ignored_device_types_are_skipped() {
+        let devices_json = format!(r#"[{}, {{"type": "Android", "titles": [
+            {{"id": 1, "name": "Minecraft", "placement": "Full", "state": "Active"}}]}}]"#,
+                                   CONSOLE_ON_HOME);
+        assert_eq!(select("xbl-ignored", DeviceSelection::default(), &devices_json), None);
+        assert_eq!(select("xbl-not-ignored", devices(&[], &[]), &devices_json),
+                   shown("Android", "Minecraft"));
+    }
+
+    #[test]
+    fn unlisted_devices_keep_api_order() {
+        let pc = r#"{"type": "WindowsOneCore", "titles": [
+            {"id": 1, "name": "Forza Horizon 3", "placement": "Full", "state": "Active"}]}"#;
+        let phone = r#"{"type": "iOS", "titles": [
+            {"id": 2, "name": "Minecraft", "placement": "Full", "state": "Active"}]}"#;
+        let selection = || devices(&["XboxOne"], &[]);
+
+        assert_eq!(select("xbl-unlisted-pc-first",
+                          selection(),
+                          &format!("[{}, {}, {}]", CONSOLE_ON_HOME, pc, phone)),
+                   shown("WindowsOneCore", "Forza Horizon 3"));
+        assert_eq!(select("xbl-unlisted-phone-first",
+                          selection(),
+                          &format!("[{}, {}, {}]", phone, CONSOLE_ON_HOME, pc)),
+                   shown("iOS", "Minecraft"));
+    }
+
+    #[test]
+    fn reports_media_apps_as_watching() {
+        let detail = replay("xbl-watching",
+                            r#"{"state": "Online", "devices": [{"type": "XboxOne", "titles": [
+                                {"id": 1, "name": "Netflix", "placement": "Full",
+                                 "state": "Active"}]}]}"#)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(detail.game, "Netflix");
+        assert_eq!(detail.kind, ActivityKind::Watching);
+    }
+
+    #[test]
+    fn skips_system_apps() {
+        let presence = replay("xbl-home",
+                              r#"{"state": "Online", "devices": [{"type": "XboxOne", "titles": [
+                                  {"id": 714681658, "name": "Home", "placement": "Full",
+                                   "state": "Active"}]}]}"#)
+            .unwrap();
+        assert!(presence.is_none());
+    }
+
+    #[test]
+    fn offline_has_no_presence() {
+        assert!(replay("xbl-offline", r#"{"state": "Offline"}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_api_errors() {
+        assert!(replay("xbl-error", r#"{"error_code": 28, "error_message": "Bad key"}"#).is_err());
+    }
+
+    #[test]
+    fn accounts_on_one_key_share_the_quota() {
+        let mut headers = Headers::new();
+        headers.set(XRateLimitLimit(100));
+        headers.set(XRateLimitRemaining(10));
+        headers.set(XRateLimitReset(100));
+
+        let mut first = keyed_provider("shared-key", PathBuf::new());
+        first.update_rate_limit(&headers);
+        let alone = first.min_update_interval().unwrap();
+        assert!(alone > Duration::from_secs(9) && alone <= Duration::from_secs(10));
+
+        let second = keyed_provider("shared-key", PathBuf::new());
+        let shared = second.min_update_interval().unwrap();
+        assert!(shared > Duration::from_secs(19) && shared <= Duration::from_secs(20));
+
+        drop(second);
+        assert!(first.min_update_interval().unwrap() <= Duration::from_secs(10));
+        assert!(keyed_provider("other-key", PathBuf::new()).min_update_interval().is_none());
+    }
+}
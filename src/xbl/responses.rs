@@ -6,6 +6,7 @@ pub struct Activity {
 
 #[derive(Deserialize, Debug)]
 pub struct Title {
+    pub id: Option<u64>,
     pub name: String,
     pub placement: String,
     pub state: String,
@@ -16,6 +17,7 @@ pub struct Title {
 pub struct Device {
     #[serde(rename = "type")]
     pub name: String,
+    #[serde(default)]
     pub titles: Vec<Title>,
 }
 